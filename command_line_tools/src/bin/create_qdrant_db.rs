@@ -4,6 +4,7 @@ use std::io::{BufRead, BufReader};
 use std::time::Duration;
 
 use anyhow::Result;
+use api_types::chunk_meta::{chunk_types, section_title};
 use qdrant_client::client::Payload;
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::vectors_config::Config;
@@ -47,23 +48,39 @@ async fn main() -> Result<()> {
 
     let embedding_data_file =
         BufReader::new(File::open("./test_doc/embedding.jsonl").expect("can open embedding.jsonl"));
-    let mut all_doc_records = Vec::<DocumentRecord>::new();
-    let points = embedding_data_file
+    let all_doc_records = embedding_data_file
         .lines()
         .map(|line| {
-            let r: DocumentRecord =
-                serde_json::from_str(line.unwrap().as_str()).expect("failed json conversion");
-            let r_keep = r.clone();
-            all_doc_records.push(r_keep);
+            serde_json::from_str::<DocumentRecord>(line.unwrap().as_str())
+                .expect("failed json conversion")
+        })
+        .collect::<Vec<_>>();
 
+    // the section title only appears in the first chunk of a section
+    let mut sec_to_title = HashMap::<(usize, usize), String>::new();
+    all_doc_records.iter().for_each(|r| {
+        if let Some(title) = section_title(&r.text) {
+            sec_to_title
+                .entry((r.document_id, r.section_id))
+                .or_insert(title);
+        }
+    });
+
+    let points = all_doc_records
+        .iter()
+        .map(|r| {
             let mut payload = Payload::new();
-            payload.insert("file_name", r.file_name);
+            payload.insert("file_name", r.file_name.clone());
             payload.insert("document_id", r.document_id.to_string());
             payload.insert("section_id", r.section_id.to_string());
             payload.insert("chunk_id", r.chunk_id.to_string());
-            payload.insert("text", r.text);
+            if let Some(title) = sec_to_title.get(&(r.document_id, r.section_id)) {
+                payload.insert("section_title", title.clone());
+            }
+            payload.insert("chunk_types", chunk_types(&r.text));
+            payload.insert("text", r.text.clone());
             let id = r.document_id << 32 | r.section_id << 16 | r.section_id;
-            PointStruct::new(id as u64, r.embedding_vec, payload)
+            PointStruct::new(id as u64, r.embedding_vec.clone(), payload)
         })
         .collect::<Vec<_>>();
 
@@ -122,9 +139,20 @@ async fn main() -> Result<()> {
         
         let mut payload = Payload::new();
 
+        let mut sec_chunk_types = records
+            .iter()
+            .flat_map(|r| chunk_types(&r.text))
+            .collect::<Vec<_>>();
+        sec_chunk_types.sort();
+        sec_chunk_types.dedup();
+
         payload.insert("file_name", file_name.clone());
         payload.insert("document_id", doc_id.to_string());
         payload.insert("section_id", sec_id.to_string());
+        if let Some(title) = sec_to_title.get(&(*doc_id, *sec_id)) {
+            payload.insert("section_title", title.clone());
+        }
+        payload.insert("chunk_types", sec_chunk_types);
         let id = doc_id << 32 | sec_id << 16;
         PointStruct::new(id as u64, mean_vec, payload)
     }).collect::<Vec<_>>();
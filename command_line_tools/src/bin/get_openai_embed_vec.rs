@@ -3,10 +3,9 @@ use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::PathBuf;
 
+use api_types::nxml::nxml_sections;
 use glob::glob;
 use llm_chain::traits::Embeddings;
-use quick_xml::events::Event;
-use quick_xml::reader::Reader;
 use serde::{Deserialize, Serialize};
 use tiktoken_rs::p50k_base;
 
@@ -33,88 +32,7 @@ fn get_nxml_text(path: &PathBuf) -> Vec<String> {
     let mut file = BufReader::new(File::open(path).expect("file open error"));
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).expect("file reading error");
-    let text = String::from_utf8_lossy(&buf[..]);
-    let mut reader = Reader::from_str(&text);
-    reader.trim_text(true);
-    let mut buf = Vec::new();
-    let mut txt = Vec::new();
-    let mut sec_txt = Vec::new();
-
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            Ok(Event::Start(e)) => match e.name().as_ref() {
-                b"title" => {
-                    let span = reader.read_text(e.name()).expect("err");
-                    let just_string = remove_xml_tags(&span, "");
-                    sec_txt.push(
-                        [String::from("title: =="), just_string, String::from("==")].join(" "),
-                    );
-                }
-                b"p" => {
-                    let span = reader.read_text(e.name()).expect("err");
-                    let just_string = remove_xml_tags(&span, "");
-                    sec_txt.push(
-                        [
-                            String::from("content: START"),
-                            just_string,
-                            String::from("END"),
-                        ]
-                        .join(" "),
-                    );
-                }
-                b"table" => {
-                    let span = reader.read_text(e.name()).expect("err");
-                    let just_string = remove_xml_tags(&span, "");
-                    sec_txt.push([String::from("table:\n"), just_string].join(""));
-                }
-                b"list" => {
-                    let span = reader.read_text(e.name()).expect("err");
-                    let just_string = remove_xml_tags(&span, " ");
-                    sec_txt.push([String::from("list:\n"), just_string].join(""));
-                }
-                b"ref" => {
-                    let span = reader.read_text(e.name()).expect("err");
-                    let just_string = remove_xml_tags(&span, " ");
-                    sec_txt.push([String::from("reference: "), just_string].join(""));
-                }
-                _ => (),
-            },
-            Ok(Event::End(e)) => {
-                if b"sec" == e.name().as_ref() {
-                    txt.push(sec_txt.join("\n"));
-                    //txt.push(String::from("\n\n"));
-                    sec_txt.clear();
-                }
-            }
-            Ok(Event::Eof) => break,
-            _ => (),
-        }
-        buf.clear();
-    }
-    txt
-}
-
-fn remove_xml_tags(xml: &str, sep: &str) -> String {
-    let mut reader = Reader::from_str(xml);
-    reader.trim_text(true);
-    let mut buf = Vec::new();
-    let mut txt = Vec::new();
-
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            Ok(Event::Start(_)) => continue,
-            Ok(Event::Text(e)) => {
-                txt.push(String::from_utf8_lossy(e.as_ref()).into_owned());
-            }
-            Ok(Event::Eof) => break,
-            _ => (),
-        }
-        buf.clear();
-    }
-
-    txt.join(sep)
+    nxml_sections(&String::from_utf8_lossy(&buf[..])).expect("nxml parsing error")
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -151,8 +69,11 @@ async fn main() {
                     .split_by_token(&section, false)
                     .expect("text split error");
 
+                // a window starting inside the overlap of the previous one
+                // would hold nothing new
                 let chunks = (0..tokens.len())
                     .step_by(max_tokens_per_chunk - chunk_overlap)
+                    .take_while(|start| *start == 0 || start + chunk_overlap < tokens.len())
                     .map(|start| {
                         let end = if start + max_tokens_per_chunk > tokens.len() {
                             tokens.len()
@@ -0,0 +1,494 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use qdrant_client::client::Payload;
+use qdrant_client::prelude::*;
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::vectors::VectorsOptions;
+use qdrant_client::qdrant::vectors_config::Config;
+use qdrant_client::qdrant::with_payload_selector::SelectorOptions as PayloadSelectorOptions;
+use qdrant_client::qdrant::with_vectors_selector::SelectorOptions as VectorsSelectorOptions;
+use qdrant_client::qdrant::{
+    CreateCollection, PointId, ScrollPoints, VectorParams, VectorsConfig, WithPayloadSelector,
+    WithVectorsSelector,
+};
+use serde::{Deserialize, Serialize};
+
+const SNAPSHOT_FORMAT: &str = "llm_playground.collection.v1";
+const SCROLL_BATCH: u32 = 256;
+
+#[derive(Parser, Debug)]
+#[clap(name = "qdrant_snapshot")]
+/// dump a collection to a portable gzipped jsonl file, or restore one
+struct CmdOptions {
+    #[clap(long, default_value = "http://localhost:6334")]
+    qdrant_url: String,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// write the vectors, payloads and collection config to a `.jsonl.gz` file
+    Export {
+        #[clap(long)]
+        collection: String,
+        #[clap(long)]
+        output: String,
+        /// the model used to compute the vectors, recorded in the file header
+        #[clap(long, default_value = "text-embedding-ada-002")]
+        embedding_model: String,
+    },
+    /// create a collection from an exported file and upload its points
+    Import {
+        #[clap(long)]
+        input: String,
+        /// import under another name instead of the exported collection name
+        #[clap(long)]
+        collection: Option<String>,
+        /// drop the collection first if it already exists
+        #[clap(long)]
+        recreate: bool,
+        /// refuse to import vectors computed by another embedding model
+        #[clap(long)]
+        expect_embedding_model: Option<String>,
+    },
+}
+
+// first line of the file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SnapshotHeader {
+    format: String,
+    collection_name: String,
+    vector_size: u64,
+    distance: i32,
+    on_disk: Option<bool>,
+    embedding_model: String,
+    points_count: u64,
+    exported_at: u64,
+}
+
+// one line per point after the header
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SnapshotPoint {
+    id: serde_json::Value,
+    vector: Vec<f32>,
+    payload: serde_json::Value,
+}
+
+fn write_line(writer: &mut impl Write, line: &impl Serialize) -> Result<()> {
+    writeln!(writer, "{}", serde_json::to_string(line)?)?;
+    Ok(())
+}
+
+// The points after the header. A file holding more or fewer points than its
+// header counts fails after the last one.
+struct SnapshotPoints<R> {
+    lines: Lines<R>,
+    expected: u64,
+    read: u64,
+}
+
+impl<R: BufRead> Iterator for SnapshotPoints<R> {
+    type Item = Result<SnapshotPoint>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.lines.next() {
+            Some(line) => {
+                self.read += 1;
+                Some(line.map_err(|e| e.into()).and_then(|line| {
+                    serde_json::from_str(&line).map_err(|e| anyhow!("point {}: {}", self.read, e))
+                }))
+            }
+            None if self.read != self.expected => {
+                let error = anyhow!(
+                    "the snapshot holds {} points, its header says {}",
+                    self.read,
+                    self.expected
+                );
+                self.expected = self.read;
+                Some(Err(error))
+            }
+            None => None,
+        }
+    }
+}
+
+fn read_snapshot<R: BufRead>(reader: R) -> Result<(SnapshotHeader, SnapshotPoints<R>)> {
+    let mut lines = reader.lines();
+    let header: SnapshotHeader = match lines.next() {
+        Some(line) => serde_json::from_str(line?.as_str())?,
+        None => bail!("the snapshot is empty"),
+    };
+    if header.format != SNAPSHOT_FORMAT {
+        bail!("unsupported snapshot format {}", header.format);
+    }
+    let points = SnapshotPoints {
+        lines,
+        expected: header.points_count,
+        read: 0,
+    };
+    Ok((header, points))
+}
+
+fn point_id_to_json(id: Option<PointId>) -> Result<serde_json::Value> {
+    match id.and_then(|id| id.point_id_options) {
+        Some(PointIdOptions::Num(n)) => Ok(serde_json::Value::from(n)),
+        Some(PointIdOptions::Uuid(u)) => Ok(serde_json::Value::from(u)),
+        None => Err(anyhow!("point without id")),
+    }
+}
+
+fn point_id_from_json(id: &serde_json::Value) -> Result<PointId> {
+    match id {
+        serde_json::Value::Number(n) => n
+            .as_u64()
+            .map(PointId::from)
+            .ok_or_else(|| anyhow!("invalid point id {}", n)),
+        serde_json::Value::String(u) => Ok(PointId::from(u.clone())),
+        _ => Err(anyhow!("invalid point id {}", id)),
+    }
+}
+
+async fn export(
+    client: &QdrantClient,
+    collection: &str,
+    output: &str,
+    embedding_model: &str,
+) -> Result<()> {
+    let info = client
+        .collection_info(collection)
+        .await?
+        .result
+        .ok_or_else(|| anyhow!("collection {} not found", collection))?;
+    let params = info
+        .config
+        .and_then(|c| c.params)
+        .and_then(|p| p.vectors_config)
+        .and_then(|v| v.config);
+    let Some(Config::Params(params)) = params else {
+        bail!("only collections with a single unnamed vector can be exported");
+    };
+
+    let header = SnapshotHeader {
+        format: SNAPSHOT_FORMAT.to_string(),
+        collection_name: collection.to_string(),
+        vector_size: params.size,
+        distance: params.distance,
+        on_disk: params.on_disk,
+        embedding_model: embedding_model.to_string(),
+        points_count: info.points_count,
+        exported_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+
+    let mut writer = GzEncoder::new(
+        BufWriter::new(File::create(output)?),
+        Compression::default(),
+    );
+    write_line(&mut writer, &header)?;
+
+    let mut offset: Option<PointId> = None;
+    let mut exported = 0_u64;
+    loop {
+        let response = client
+            .scroll(&ScrollPoints {
+                collection_name: collection.to_string(),
+                offset: offset.clone(),
+                limit: Some(SCROLL_BATCH),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(PayloadSelectorOptions::Enable(true)),
+                }),
+                with_vectors: Some(WithVectorsSelector {
+                    selector_options: Some(VectorsSelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            })
+            .await?;
+
+        for p in response.result {
+            let vector = match p.vectors.and_then(|v| v.vectors_options) {
+                Some(VectorsOptions::Vector(v)) => v.data,
+                _ => bail!("point without a vector in {}", collection),
+            };
+            let point = SnapshotPoint {
+                id: point_id_to_json(p.id)?,
+                vector,
+                payload: serde_json::to_value(&p.payload)?,
+            };
+            write_line(&mut writer, &point)?;
+            exported += 1;
+        }
+
+        offset = response.next_page_offset;
+        if offset.is_none() {
+            break;
+        }
+    }
+    writer.finish()?.flush()?;
+    if exported != header.points_count {
+        bail!(
+            "{} changed during the export, {} points were written instead of {}",
+            collection,
+            exported,
+            header.points_count
+        );
+    }
+    println!(
+        "{} points of {} written to {}",
+        exported, collection, output
+    );
+
+    Ok(())
+}
+
+async fn import(
+    client: &QdrantClient,
+    input: &str,
+    collection: Option<String>,
+    recreate: bool,
+    expect_embedding_model: Option<String>,
+) -> Result<()> {
+    let reader = BufReader::new(GzDecoder::new(File::open(input)?));
+    let (header, points) = read_snapshot(reader).map_err(|e| anyhow!("{}: {}", input, e))?;
+    if let Some(model) = expect_embedding_model {
+        if model != header.embedding_model {
+            bail!(
+                "the snapshot was embedded with {}, not {}",
+                header.embedding_model,
+                model
+            );
+        }
+    }
+    println!(
+        "importing {} points of {} (embedding model: {})",
+        header.points_count, header.collection_name, header.embedding_model
+    );
+
+    let collection_name = collection.unwrap_or(header.collection_name.clone());
+    let exists = client
+        .list_collections()
+        .await?
+        .collections
+        .iter()
+        .any(|c| c.name == collection_name);
+    if exists {
+        if !recreate {
+            bail!(
+                "collection {} exists, use --recreate to replace it",
+                collection_name
+            );
+        }
+        client.delete_collection(&collection_name).await?;
+    }
+    client
+        .create_collection(&CreateCollection {
+            collection_name: collection_name.clone(),
+            vectors_config: Some(VectorsConfig {
+                config: Some(Config::Params(VectorParams {
+                    size: header.vector_size,
+                    distance: header.distance,
+                    hnsw_config: None,
+                    quantization_config: None,
+                    on_disk: header.on_disk,
+                })),
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+    // a failed import leaves no partial collection behind
+    let imported = match upload(client, &collection_name, points).await {
+        Ok(imported) => imported,
+        Err(e) => {
+            client.delete_collection(&collection_name).await?;
+            bail!("{}: {}, {} was dropped", input, e, collection_name);
+        }
+    };
+    println!("{} points stored in {}", imported, collection_name);
+
+    Ok(())
+}
+
+// upload the points in batches, and check the collection ends up with all of
+// them; repeated ids would collapse into one point
+async fn upload(
+    client: &QdrantClient,
+    collection_name: &str,
+    snapshot_points: impl Iterator<Item = Result<SnapshotPoint>>,
+) -> Result<u64> {
+    let mut imported = 0_u64;
+    let mut points = Vec::new();
+    for point in snapshot_points {
+        let point = point?;
+        let payload = Payload::try_from(point.payload).map_err(|e| anyhow!("{:?}", e))?;
+        points.push(PointStruct::new(
+            point_id_from_json(&point.id)?,
+            point.vector,
+            payload,
+        ));
+        if points.len() == SCROLL_BATCH as usize {
+            imported += points.len() as u64;
+            client
+                .upsert_points_blocking(collection_name, std::mem::take(&mut points), None)
+                .await?;
+        }
+    }
+    if !points.is_empty() {
+        imported += points.len() as u64;
+        client
+            .upsert_points_blocking(collection_name, points, None)
+            .await?;
+    }
+    let stored = client
+        .collection_info(collection_name)
+        .await?
+        .result
+        .map_or(0, |info| info.points_count);
+    if stored != imported {
+        bail!(
+            "{} points were uploaded but {} are stored",
+            imported,
+            stored
+        );
+    }
+    Ok(imported)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = CmdOptions::parse();
+
+    let mut config = QdrantClientConfig::from_url(&args.qdrant_url);
+    config.set_timeout(Duration::new(50000, 0));
+    let client = QdrantClient::new(Some(config))?;
+
+    match args.command {
+        Command::Export {
+            collection,
+            output,
+            embedding_model,
+        } => export(&client, &collection, &output, &embedding_model).await,
+        Command::Import {
+            input,
+            collection,
+            recreate,
+            expect_embedding_model,
+        } => {
+            import(
+                &client,
+                &input,
+                collection,
+                recreate,
+                expect_embedding_model,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn header(points_count: u64) -> SnapshotHeader {
+        SnapshotHeader {
+            format: SNAPSHOT_FORMAT.to_string(),
+            collection_name: "GeneReviews".to_string(),
+            vector_size: 3,
+            distance: 3,
+            on_disk: Some(true),
+            embedding_model: "text-embedding-ada-002".to_string(),
+            points_count,
+            exported_at: 1_700_000_000,
+        }
+    }
+
+    fn points() -> Vec<SnapshotPoint> {
+        vec![
+            SnapshotPoint {
+                id: json!(7),
+                vector: vec![0.25, -1.5, 3.0],
+                payload: json!({"text": "NARS2", "pmcid": "PMC1", "chunk_index": 2}),
+            },
+            SnapshotPoint {
+                id: json!("5c56c793-69f3-4fbf-87e6-c4bf54c28c26"),
+                vector: vec![0.1, 0.2, 0.3],
+                payload: json!({"text": "hearing loss", "keywords": ["DFNB94"]}),
+            },
+        ]
+    }
+
+    // the gzipped file an export writes
+    fn snapshot(header: &SnapshotHeader, points: &[SnapshotPoint]) -> Vec<u8> {
+        let mut writer = GzEncoder::new(Vec::new(), Compression::default());
+        write_line(&mut writer, header).unwrap();
+        for point in points {
+            write_line(&mut writer, point).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read(bytes: &[u8]) -> Result<(SnapshotHeader, Vec<SnapshotPoint>)> {
+        let (header, points) = read_snapshot(BufReader::new(GzDecoder::new(bytes)))?;
+        Ok((header, points.collect::<Result<_>>()?))
+    }
+
+    #[test]
+    fn header_and_points_roundtrip() {
+        let bytes = snapshot(&header(2), &points());
+        let (read_header, read_points) = read(&bytes).unwrap();
+        assert_eq!(read_header, header(2));
+        assert_eq!(read_points, points());
+    }
+
+    #[test]
+    fn point_ids_roundtrip() {
+        for point in points() {
+            let id = point_id_from_json(&point.id).unwrap();
+            assert_eq!(point_id_to_json(Some(id)).unwrap(), point.id);
+        }
+        assert!(point_id_from_json(&json!(-1)).is_err());
+        assert!(point_id_from_json(&json!(null)).is_err());
+        assert!(point_id_to_json(None).is_err());
+    }
+
+    #[test]
+    fn fails_on_a_count_mismatch() {
+        for count in [1, 3] {
+            let bytes = snapshot(&header(count), &points());
+            let error = read(&bytes).unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!("the snapshot holds 2 points, its header says {}", count)
+            );
+        }
+        // the points before the end still come through
+        let bytes = snapshot(&header(3), &points());
+        let (_, points) = read_snapshot(BufReader::new(GzDecoder::new(&bytes[..]))).unwrap();
+        let read = points.collect::<Vec<_>>();
+        assert_eq!(read.len(), 3);
+        assert!(read[..2].iter().all(|p| p.is_ok()));
+    }
+
+    #[test]
+    fn rejects_another_format_and_an_empty_file() {
+        let mut other = header(0);
+        other.format = "llm_playground.collection.v0".to_string();
+        let error = read(&snapshot(&other, &[])).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "unsupported snapshot format llm_playground.collection.v0"
+        );
+        assert!(read_snapshot(Cursor::new("")).is_err());
+    }
+}
@@ -4,11 +4,13 @@ use std::io::{BufRead, BufReader};
 use std::time::Duration;
 
 use anyhow::Result;
+use api_types::filters::{all_of, and, any_of, match_keyword, Level};
+use api_types::{ChunkType, SearchFilters};
 use clap::Parser;
 use llm_chain::traits::Embeddings;
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::with_payload_selector::SelectorOptions;
-use qdrant_client::qdrant::WithPayloadSelector;
+use qdrant_client::qdrant::{Filter, SearchResponse, WithPayloadSelector};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 use serde::{Deserialize, Serialize};
@@ -30,6 +32,140 @@ struct DocumentRecord {
 struct CmdOptions {
     #[clap(long, short, default_value_t = 5)]
     topn: u64,
+    /// search documents, then their sections, then the chunks of those sections
+    #[clap(long)]
+    hierarchical: bool,
+    /// number of documents kept at the first level of the hierarchical search
+    #[clap(long, default_value_t = 5)]
+    top_documents: u64,
+    /// number of sections kept at the second level of the hierarchical search
+    #[clap(long, default_value_t = 10)]
+    top_sections: u64,
+    /// only search these document ids (repeatable)
+    #[clap(long = "document-id")]
+    document_ids: Vec<usize>,
+    /// only search these files, e.g. `NBK1116.nxml` (repeatable)
+    #[clap(long = "file-name")]
+    file_names: Vec<String>,
+    /// only search documents whose keyword groups mention this term (repeatable)
+    #[clap(long = "keyword")]
+    keywords: Vec<String>,
+    /// only search sections whose title contains this text (repeatable)
+    #[clap(long = "section-title")]
+    section_titles: Vec<String>,
+    /// only search chunks containing a paragraph, table, list or reference (repeatable)
+    #[clap(long = "chunk-type")]
+    chunk_types: Vec<ChunkType>,
+}
+
+impl CmdOptions {
+    fn search_filter(&self) -> SearchFilters {
+        SearchFilters {
+            document_ids: self.document_ids.clone(),
+            file_names: self.file_names.clone(),
+            keywords: self.keywords.clone(),
+            section_titles: self.section_titles.clone(),
+            chunk_types: self.chunk_types.clone(),
+        }
+    }
+}
+
+fn payload_id(p: &qdrant_client::qdrant::ScoredPoint, key: &str) -> Option<String> {
+    p.payload.get(key).map(|v| {
+        serde_json::to_string(v)
+            .expect("json conversion fails")
+            .trim_matches('"')
+            .to_string()
+    })
+}
+
+async fn search(
+    client: &QdrantClient,
+    collection_name: &str,
+    vector: Vec<f32>,
+    filter: Option<Filter>,
+    limit: u64,
+) -> Result<SearchResponse> {
+    client
+        .search_points(&SearchPoints {
+            collection_name: collection_name.into(),
+            vector,
+            filter,
+            limit,
+            with_vectors: None,
+            with_payload: Some(WithPayloadSelector {
+                selector_options: Some(SelectorOptions::Enable(true)),
+            }),
+            params: None,
+            score_threshold: None,
+            offset: None,
+            ..Default::default()
+        })
+        .await
+}
+
+async fn hierarchical_search(
+    client: &QdrantClient,
+    vector: Vec<f32>,
+    args: &CmdOptions,
+    fn_to_keywords: &HashMap<String, Vec<String>>,
+) -> Result<SearchResponse> {
+    let search_filter = args.search_filter();
+    let documents = search(
+        client,
+        "NBK1116_documents",
+        vector.clone(),
+        search_filter.to_qdrant_filter(fn_to_keywords, Level::Documents),
+        args.top_documents,
+    )
+    .await?;
+    let document_conditions = documents
+        .result
+        .iter()
+        .filter_map(|p| payload_id(p, "document_id"))
+        .map(|doc_id| match_keyword("document_id", doc_id))
+        .collect::<Vec<_>>();
+    // an empty `should` matches every point
+    if document_conditions.is_empty() {
+        return Ok(SearchResponse::default());
+    }
+
+    let sections = search(
+        client,
+        "NBK1116_sections",
+        vector.clone(),
+        and(
+            search_filter.to_qdrant_filter(fn_to_keywords, Level::Sections),
+            vec![any_of(document_conditions)],
+        ),
+        args.top_sections,
+    )
+    .await?;
+    let section_conditions = sections
+        .result
+        .iter()
+        .filter_map(|p| {
+            Some(all_of(vec![
+                match_keyword("document_id", payload_id(p, "document_id")?),
+                match_keyword("section_id", payload_id(p, "section_id")?),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    if section_conditions.is_empty() {
+        return Ok(SearchResponse::default());
+    }
+
+    search(
+        client,
+        "NBK1116_chunks",
+        vector,
+        and(
+            search_filter.to_qdrant_filter(fn_to_keywords, Level::Chunks),
+            vec![any_of(section_conditions)],
+        ),
+        args.topn,
+    )
+    .await
 }
 
 #[tokio::main]
@@ -70,22 +206,16 @@ async fn main() -> Result<()> {
                     let embedded_vecs = embeddings.embed_texts(vec![query_str]).await.unwrap();
 
                     //println!("{}", points[1120].text);
-                    let search_result = client
-                        .search_points(&SearchPoints {
-                            collection_name: collection_name.into(),
-                            vector: embedded_vecs[0].clone(),
-                            filter: None,
-                            limit: args.topn,
-                            with_vectors: None,
-                            with_payload: Some(WithPayloadSelector {
-                                selector_options: Some(SelectorOptions::Enable(true)),
-                            }),
-                            params: None,
-                            score_threshold: None,
-                            offset: None,
-                            ..Default::default()
-                        })
-                        .await?;
+                    let search_result = if args.hierarchical {
+                        hierarchical_search(&client, embedded_vecs[0].clone(), &args, &fn_to_keywords)
+                            .await?
+                    } else {
+                        let filter = args
+                            .search_filter()
+                            .to_qdrant_filter(&fn_to_keywords, Level::Chunks);
+                        search(&client, collection_name, embedded_vecs[0].clone(), filter, args.topn)
+                            .await?
+                    };
                     //dbg!(search_result);
                     search_result.result.into_iter().for_each(|p| {
                         //let payload = serde_json::to_string(&p.payload).expect("json conversion fails");
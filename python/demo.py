@@ -1,34 +1,65 @@
 import gradio as gr
-import random
-import time
-import requests
 import json
+import os
+from urllib.parse import urlencode
+
+from websockets.sync.client import connect
+
+# sent when the server requires API keys
+headers = {"X-API-Key": os.environ["LLM_PLAYGROUND_API_KEY"]} if "LLM_PLAYGROUND_API_KEY" in os.environ else {}
 
 with gr.Blocks() as demo:
 
 
     chatbot = gr.Chatbot()
+    # the server side conversation, follow-up questions are asked in it
+    session_id = gr.State(None)
     msg = gr.Textbox(label="Input your query about Gene Review")
     query_type = gr.Dropdown(choices=["Summary", "Q&A"], value="Summary", type="index", label="Query Type")
-    clear = gr.ClearButton([msg, chatbot])
-
-    def respond(message, chat_history, dropdown):
-        msg = {"text":message, "topn":3}
-        if dropdown == 0:
-            r = requests.post('http://127.0.0.1:3000/api/post_query_for_summary_of_a_topic', json = msg)
-        else:
-            r = requests.post('http://127.0.0.1:3000/api/post_query_for_answer_of_a_question', json = msg)
+    stop = gr.Button("Stop")
+    clear = gr.ClearButton([msg, chatbot, session_id])
 
+    def respond(message, chat_history, dropdown, session):
+        kind = "summary" if dropdown == 0 else "question"
+        params = urlencode({"session_id": session}) if session else ""
+        chat_history.append((message, ""))
+        # the messages are the `ClientMessage` and `ServerMessage` schemas of /api/openapi.json
+        with connect("ws://127.0.0.1:3000/api/v1/ws?" + params, additional_headers=headers) as ws:
+            ws.send(json.dumps({"type": "query", "kind": kind, "query": {"text": message, "topn": 3}}))
+            try:
+                for raw in ws:
+                    event = json.loads(raw)
+                    if event["type"] == "session":
+                        session = event["session_id"]
+                    elif event["type"] == "token":
+                        chat_history[-1] = (message, chat_history[-1][1] + event["text"])
+                        yield "", chat_history, session
+                    elif event["type"] == "error":
+                        # {"code", "message", "retryable"}
+                        error = event["error"]
+                        hint = " Please try again." if error["retryable"] else ""
+                        chat_history[-1] = (message, "Error ({}): {}.{}".format(error["code"], error["message"], hint))
+                        break
+                    elif event["type"] == "cancelled":
+                        break
+                    elif event["type"] == "done":
+                        # {"text", "sources", "unknown_citations", "usage"}, the text cites the sources as [n]
+                        answer = event["answer"]
+                        cited = ["[{}] {}".format(s["index"], s["url"]) for s in answer["sources"] if s["cited"]]
+                        text = answer["text"]
+                        if cited:
+                            text += "\n\nSources:\n" + "\n".join(cited)
+                        if answer["unknown_citations"]:
+                            text += "\n\nWarning: unknown citations {}".format(answer["unknown_citations"])
+                        chat_history[-1] = (message, text)
+                        break
+            except GeneratorExit:
+                # "Stop" closes the generator, the server stops generating
+                ws.send(json.dumps({"type": "cancel"}))
+                raise
+        yield "", chat_history, session
 
-        bot_message = json.loads(r.text) 
-        if bot_message is not None:
-            bot_message = bot_message[0]["text"]
-            chat_history.append((message, bot_message))
-            
-        time.sleep(2)
-        return "", chat_history
-
-    msg.submit(respond, [msg, chatbot, query_type], [msg, chatbot])
+    answering = msg.submit(respond, [msg, chatbot, query_type, session_id], [msg, chatbot, session_id])
+    stop.click(None, None, None, cancels=[answering])
 
 demo.launch()
-
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub retryable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    pub latency_ms: u64,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize, latency_ms: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            latency_ms,
+        }
+    }
+}
+
+// A retrieved record as it was numbered in the prompt context.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Source {
+    /// the `[n]` the model cites it with, starting at 1
+    pub index: usize,
+    pub url: Option<String>,
+    pub file_name: Option<String>,
+    pub document_id: Option<usize>,
+    pub section_id: Option<usize>,
+    pub chunk_id: Option<usize>,
+    pub score: Option<f32>,
+    pub rerank_score: Option<f32>,
+    /// the answer cites this source at least once
+    pub cited: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum PackReason {
+    /// same record, a chunk of a kept section, or text already in a kept block
+    Duplicate,
+    /// no room left in the budget
+    OverBudget,
+    /// next to or overlapping a kept chunk of the same section, appended to it
+    Merged,
+    /// kept, but cut to the remaining budget
+    Trimmed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BlockReport {
+    pub document_id: Option<usize>,
+    pub section_id: Option<usize>,
+    pub chunk_id: Option<usize>,
+    pub score: Option<f32>,
+    /// size of the full block
+    pub tokens: usize,
+    pub reason: PackReason,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ContextReport {
+    /// tokens available to the context once the system prompt, history,
+    /// template and question are accounted for
+    pub budget: usize,
+    pub used_tokens: usize,
+    /// chunks joined to a kept block, `tokens` is what they added to it
+    #[serde(default)]
+    pub merged: Vec<BlockReport>,
+    /// blocks cut to fit, overflow is trimmed and never summarised
+    pub trimmed: Vec<BlockReport>,
+    pub dropped: Vec<BlockReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Answer {
+    /// the model output, citing the sources as `[n]`
+    pub text: String,
+    pub sources: Vec<Source>,
+    /// `[n]` markers in the text that match none of the sources
+    pub unknown_citations: Vec<usize>,
+    /// what was left out of the prompt to fit the token budget
+    pub context: ContextReport,
+    /// the model that wrote the answer, a fallback if the requested one failed
+    pub model: String,
+    /// `name@vN` id of the prompt template
+    pub prompt: String,
+    pub usage: Usage,
+    /// id of the audit log entry, what `/api/v1/feedback` refers to
+    pub log_id: Option<i64>,
+    /// served from the answer cache, `usage` has no tokens then
+    #[serde(default)]
+    pub cache_hit: bool,
+}
+
+// a `token` event of the streaming endpoints
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TokenEvent {
+    pub text: String,
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+use crate::search::DocumentRecord;
+
+// a retrieved record as the log keeps it, ids and scores without the text
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RetrievedPoint {
+    pub document_id: Option<usize>,
+    pub section_id: Option<usize>,
+    pub chunk_id: Option<usize>,
+    pub score: Option<f32>,
+    pub rerank_score: Option<f32>,
+}
+
+impl From<&DocumentRecord> for RetrievedPoint {
+    fn from(record: &DocumentRecord) -> Self {
+        Self {
+            document_id: record.document_id,
+            section_id: record.section_id,
+            chunk_id: record.chunk_id,
+            score: record.score,
+            rerank_score: record.rerank_score,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    // how the rating is stored, 1 or -1
+    pub fn value(self) -> i64 {
+        match self {
+            Rating::Up => 1,
+            Rating::Down => -1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FeedbackRequest {
+    /// the `log_id` of the answer
+    pub log_id: i64,
+    pub rating: Rating,
+    pub comment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct Feedback {
+    pub feedback_id: i64,
+    pub log_id: i64,
+    /// 1 for thumbs up, -1 for thumbs down
+    pub rating: i64,
+    pub comment: Option<String>,
+    pub created_at: i64,
+}
+
+// one line of the export, a logged request with its feedback
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LogRecord {
+    pub log_id: i64,
+    pub created_at: i64,
+    pub endpoint: String,
+    pub query: String,
+    pub session_id: Option<String>,
+    pub standalone_query: Option<String>,
+    pub retrieved: Vec<RetrievedPoint>,
+    pub prompt: Option<String>,
+    pub model: Option<String>,
+    pub answer: Option<String>,
+    /// `code: message` of a failed request
+    pub error: Option<String>,
+    pub latency_ms: i64,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    /// name of the API key of the request
+    pub api_key: Option<String>,
+    pub feedback: Vec<Feedback>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct ExportRange {
+    /// unix seconds, inclusive
+    pub since: Option<i64>,
+    /// unix seconds, exclusive
+    pub until: Option<i64>,
+}
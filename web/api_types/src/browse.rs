@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_PAGE_SIZE: usize = 500;
+
+// `?offset=&limit=` of the browsing endpoints
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+#[serde(default)]
+pub struct Pagination {
+    pub offset: usize,
+    /// at most 500, 50 when unset
+    pub limit: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: 50,
+        }
+    }
+}
+
+impl Pagination {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.limit == 0 || self.limit > MAX_PAGE_SIZE {
+            return Err(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+        Ok(())
+    }
+
+    pub fn page<T>(&self, items: Vec<T>) -> Page<T> {
+        Page {
+            total: items.len(),
+            offset: self.offset,
+            limit: self.limit,
+            items: items
+                .into_iter()
+                .skip(self.offset)
+                .take(self.limit)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::ToSchema),
+    aliases(
+        DocumentPage = Page<DocumentInfo>,
+        SectionPage = Page<SectionInfo>,
+        ChunkPage = Page<crate::DocumentRecord>
+    )
+)]
+pub struct Page<T> {
+    /// number of items over all the pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DocumentInfo {
+    pub document_id: usize,
+    pub file_name: String,
+    pub url: String,
+    pub keywords: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SectionInfo {
+    pub document_id: usize,
+    pub section_id: usize,
+    /// the heading of the section when `create_qdrant_db` found one
+    pub section_title: Option<String>,
+    pub chunk_types: Vec<String>,
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Answer, DocumentRecord, ErrorBody, QueryText};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ChatKind {
+    /// like `post_query_for_answer_of_a_question`
+    #[default]
+    Question,
+    /// like `post_query_for_summary_of_a_topic`
+    Summary,
+}
+
+// What a client sends on `/api/v1/ws`, one query at a time per connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// `session_id` is set to the session of the connection
+    Query {
+        query: Box<QueryText>,
+        #[serde(default)]
+        kind: ChatKind,
+    },
+    /// stop the running query
+    Cancel,
+}
+
+// What the server sends on `/api/v1/ws`; the streaming endpoints send the same
+// payloads as `sources`, `token`, `done` and `error` events.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// first message, the session the queries of the connection go to
+    Session { session_id: String },
+    /// the retrieved records, in context order
+    Sources { records: Vec<DocumentRecord> },
+    Token { text: String },
+    /// the complete answer with its checked citations, usage and log id
+    Done { answer: Box<Answer> },
+    /// the running query stopped on a `cancel`
+    Cancelled,
+    Error { error: ErrorBody },
+}
+
+// the query string of `/api/v1/ws`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatParams {
+    /// continue a conversation, a new session is created without it
+    pub session_id: Option<String>,
+}
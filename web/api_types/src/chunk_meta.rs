@@ -0,0 +1,60 @@
+// Metadata derived from the section markers of `nxml::nxml_sections`, stored
+// in the payload so that searches can be filtered on it. The command line
+// tools and the server's uploads both use it, the filters depend on the two
+// agreeing.
+
+pub const CHUNK_TYPES: &[(&str, &str)] = &[
+    ("content: START", "paragraph"),
+    ("table:", "table"),
+    ("list:", "list"),
+    ("reference: ", "reference"),
+];
+
+/// the title of a section, taken from the `title: == ... ==` line of its first chunk
+pub fn section_title(text: &str) -> Option<String> {
+    let start = text.find("title: ==")? + "title: ==".len();
+    let end = text[start..].find("==")? + start;
+    let title = text[start..end].trim();
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    }
+}
+
+/// the kinds of blocks (paragraph, table, list, reference) a chunk contains
+pub fn chunk_types(text: &str) -> Vec<String> {
+    CHUNK_TYPES
+        .iter()
+        .filter(|(marker, _)| text.contains(marker))
+        .map(|(_, chunk_type)| chunk_type.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_title_between_the_markers() {
+        let text = "title: == Clinical Characteristics ==\ncontent: START text END";
+        assert_eq!(
+            section_title(text),
+            Some("Clinical Characteristics".to_string())
+        );
+        // a later chunk of the section starts inside the text
+        assert_eq!(section_title("content: START more text END"), None);
+        assert_eq!(section_title("title: ==  ==\ncontent: START x END"), None);
+        assert_eq!(section_title("title: == unterminated"), None);
+    }
+
+    #[test]
+    fn lists_the_block_types_once_in_marker_order() {
+        let text = "reference: Doe 2020\ntable:\nab\ncontent: START a END\n\
+                    content: START b END";
+        assert_eq!(chunk_types(text), vec!["paragraph", "table", "reference"]);
+        assert_eq!(chunk_types("list:\none two"), vec!["list"]);
+        // the end of a window cut mid-marker doesn't count
+        assert!(chunk_types("plain text, no markers. content: STA").is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+// The Qdrant side of the search filters, used by the server and by the
+// `query_qdrant_db` command line tool.
+use std::collections::HashMap;
+
+use qdrant_client::qdrant::{
+    condition::ConditionOneOf, r#match::MatchValue, Condition, FieldCondition, Filter, Match,
+};
+
+use crate::SearchFilters;
+
+// The payload fields available in each of the three collections
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Documents,
+    Sections,
+    Chunks,
+}
+
+// the ids are stored as strings in the payload, so match them as exact keywords
+pub fn match_keyword(key: &str, value: String) -> Condition {
+    Condition {
+        condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
+            key: key.to_string(),
+            r#match: Some(Match {
+                match_value: Some(MatchValue::Keyword(value)),
+            }),
+            ..Default::default()
+        })),
+    }
+}
+
+pub fn match_text(key: &str, value: String) -> Condition {
+    Condition {
+        condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
+            key: key.to_string(),
+            r#match: Some(Match {
+                match_value: Some(MatchValue::Text(value)),
+            }),
+            ..Default::default()
+        })),
+    }
+}
+
+pub fn any_of(conditions: Vec<Condition>) -> Condition {
+    Condition {
+        condition_one_of: Some(ConditionOneOf::Filter(Filter {
+            should: conditions,
+            ..Default::default()
+        })),
+    }
+}
+
+pub fn all_of(conditions: Vec<Condition>) -> Condition {
+    Condition {
+        condition_one_of: Some(ConditionOneOf::Filter(Filter {
+            must: conditions,
+            ..Default::default()
+        })),
+    }
+}
+
+// add `conditions` to an optional filter
+pub fn and(filter: Option<Filter>, mut conditions: Vec<Condition>) -> Option<Filter> {
+    let mut filter = filter.unwrap_or_default();
+    filter.must.append(&mut conditions);
+    if filter.must.is_empty() && filter.should.is_empty() && filter.must_not.is_empty() {
+        None
+    } else {
+        Some(filter)
+    }
+}
+
+// file names whose keyword groups mention one of the requested terms
+pub fn keyword_files(
+    terms: &[String],
+    fn_to_keywords: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
+    let terms = terms.iter().map(|t| t.to_lowercase()).collect::<Vec<_>>();
+    let mut file_names = fn_to_keywords
+        .iter()
+        .filter(|(_, groups)| {
+            groups.iter().any(|group| {
+                let group = group.to_lowercase();
+                terms.iter().any(|t| group.contains(t.as_str()))
+            })
+        })
+        .map(|(file_name, _)| file_name.clone())
+        .collect::<Vec<_>>();
+    file_names.sort();
+    file_names
+}
+
+// The `_documents` collection only carries the file name and the document id,
+// the section and chunk conditions are left out at that level.
+impl SearchFilters {
+    pub fn to_qdrant_filter(
+        &self,
+        fn_to_keywords: &HashMap<String, Vec<String>>,
+        level: Level,
+    ) -> Option<Filter> {
+        let mut must = Vec::new();
+        if !self.document_ids.is_empty() {
+            must.push(any_of(
+                self.document_ids
+                    .iter()
+                    .map(|id| match_keyword("document_id", id.to_string()))
+                    .collect(),
+            ));
+        }
+        if !self.file_names.is_empty() {
+            must.push(any_of(
+                self.file_names
+                    .iter()
+                    .map(|f| match_keyword("file_name", f.clone()))
+                    .collect(),
+            ));
+        }
+        if !self.keywords.is_empty() {
+            let file_names = keyword_files(&self.keywords, fn_to_keywords);
+            if file_names.is_empty() {
+                // no document carries the keywords, nothing should match
+                must.push(match_keyword("file_name", String::new()));
+            } else {
+                must.push(any_of(
+                    file_names
+                        .into_iter()
+                        .map(|f| match_keyword("file_name", f))
+                        .collect(),
+                ));
+            }
+        }
+        if level != Level::Documents && !self.section_titles.is_empty() {
+            must.push(any_of(
+                self.section_titles
+                    .iter()
+                    .map(|t| match_text("section_title", t.clone()))
+                    .collect(),
+            ));
+        }
+        if level != Level::Documents && !self.chunk_types.is_empty() {
+            must.push(any_of(
+                self.chunk_types
+                    .iter()
+                    .map(|t| match_keyword("chunk_types", t.as_str().to_string()))
+                    .collect(),
+            ));
+        }
+        and(None, must)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkType;
+
+    fn fn_to_keywords() -> HashMap<String, Vec<String>> {
+        HashMap::from([
+            (
+                "NBK1116.nxml".to_string(),
+                vec!["Cystic Fibrosis".to_string(), "CFTR".to_string()],
+            ),
+            ("NBK1247.nxml".to_string(), vec!["NARS2".to_string()]),
+            (
+                "NBK1434.nxml".to_string(),
+                vec!["Hearing Loss, CFTR-related".to_string()],
+            ),
+        ])
+    }
+
+    fn filter(filters: &SearchFilters, level: Level) -> Option<Filter> {
+        filters.to_qdrant_filter(&fn_to_keywords(), level)
+    }
+
+    fn files(file_names: &[&str]) -> Condition {
+        any_of(
+            file_names
+                .iter()
+                .map(|f| match_keyword("file_name", f.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn no_filters_is_no_filter() {
+        for level in [Level::Documents, Level::Sections, Level::Chunks] {
+            assert_eq!(filter(&SearchFilters::default(), level), None);
+        }
+        assert_eq!(and(None, vec![]), None);
+    }
+
+    #[test]
+    fn each_field_is_one_of_its_values() {
+        let ids = SearchFilters {
+            document_ids: vec![3, 5],
+            ..Default::default()
+        };
+        let expected = any_of(vec![
+            match_keyword("document_id", "3".to_string()),
+            match_keyword("document_id", "5".to_string()),
+        ]);
+        assert_eq!(filter(&ids, Level::Chunks).unwrap().must, vec![expected]);
+
+        let names = SearchFilters {
+            file_names: vec!["NBK1247.nxml".to_string()],
+            ..Default::default()
+        };
+        let expected = files(&["NBK1247.nxml"]);
+        assert_eq!(filter(&names, Level::Chunks).unwrap().must, vec![expected]);
+
+        let titles = SearchFilters {
+            section_titles: vec!["Diagnosis".to_string()],
+            ..Default::default()
+        };
+        let expected = any_of(vec![match_text("section_title", "Diagnosis".to_string())]);
+        let sections = filter(&titles, Level::Sections).unwrap();
+        assert_eq!(sections.must, vec![expected]);
+
+        let types = SearchFilters {
+            chunk_types: vec![ChunkType::Table, ChunkType::List],
+            ..Default::default()
+        };
+        let expected = any_of(vec![
+            match_keyword("chunk_types", "table".to_string()),
+            match_keyword("chunk_types", "list".to_string()),
+        ]);
+        assert_eq!(filter(&types, Level::Chunks).unwrap().must, vec![expected]);
+        // the documents carry no sections or chunk types
+        assert_eq!(filter(&titles, Level::Documents), None);
+        assert_eq!(filter(&types, Level::Documents), None);
+    }
+
+    #[test]
+    fn keywords_select_the_files_mentioning_them() {
+        let terms = ["cftr".to_string()];
+        assert_eq!(
+            keyword_files(&terms, &fn_to_keywords()),
+            vec!["NBK1116.nxml", "NBK1434.nxml"]
+        );
+        let keywords = SearchFilters {
+            keywords: vec!["NARS2".to_string(), "hearing".to_string()],
+            ..Default::default()
+        };
+        let expected = files(&["NBK1247.nxml", "NBK1434.nxml"]);
+        let filter = filter(&keywords, Level::Documents).unwrap();
+        assert_eq!(filter.must, vec![expected]);
+    }
+
+    #[test]
+    fn unknown_keywords_match_nothing() {
+        let keywords = SearchFilters {
+            keywords: vec!["BRCA1".to_string()],
+            ..Default::default()
+        };
+        // an empty file name, which no point has
+        let expected = match_keyword("file_name", String::new());
+        let filter = filter(&keywords, Level::Chunks).unwrap();
+        assert_eq!(filter.must, vec![expected]);
+    }
+
+    #[test]
+    fn fields_combine_as_all_of() {
+        let filters = SearchFilters {
+            document_ids: vec![1],
+            file_names: vec!["NBK1116.nxml".to_string()],
+            keywords: vec!["fibrosis".to_string()],
+            section_titles: vec!["Management".to_string()],
+            chunk_types: vec![ChunkType::Paragraph],
+        };
+        let chunks = filter(&filters, Level::Chunks).unwrap();
+        assert_eq!(chunks.must.len(), 5);
+        assert!(chunks.should.is_empty() && chunks.must_not.is_empty());
+        let documents = filter(&filters, Level::Documents).unwrap();
+        assert_eq!(documents.must, chunks.must[..3].to_vec());
+
+        // more conditions go next to those of the filter
+        let hits = vec![match_keyword("document_id", "1".to_string())];
+        let combined = and(Some(documents), hits.clone()).unwrap();
+        assert_eq!(combined.must.len(), 4);
+        assert_eq!(combined.must[3], hits[0]);
+    }
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DependencyCheck {
+    /// `vector_store`, `embeddings` or `llm:<model>`
+    pub name: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+// `GET /readyz`, answered with 503 unless every check passed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<DependencyCheck>,
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentFormat {
+    Nxml,
+    Pdf,
+    Markdown,
+}
+
+impl DocumentFormat {
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        let extension = Path::new(file_name).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "nxml" | "xml" => Some(DocumentFormat::Nxml),
+            "pdf" => Some(DocumentFormat::Pdf),
+            "md" | "markdown" => Some(DocumentFormat::Markdown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Job {
+    pub job_id: u64,
+    pub file_name: String,
+    pub format: DocumentFormat,
+    pub status: JobStatus,
+    /// parsing, embedding or indexing while the job runs
+    pub stage: Option<String>,
+    /// set once the document is being indexed
+    pub document_id: Option<usize>,
+    pub sections: usize,
+    pub chunks: usize,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+}
@@ -0,0 +1,28 @@
+// The JSON the server API takes and returns. Changing a type here changes the
+// server, the frontend and `/api/openapi.json` together.
+mod answer;
+mod audit;
+mod browse;
+mod chat;
+pub mod chunk_meta;
+#[cfg(feature = "qdrant")]
+pub mod filters;
+mod health;
+mod jobs;
+#[cfg(feature = "nxml")]
+pub mod nxml;
+mod prompts;
+mod search;
+mod sessions;
+mod usage;
+
+pub use answer::*;
+pub use audit::*;
+pub use browse::*;
+pub use chat::*;
+pub use health::*;
+pub use jobs::*;
+pub use prompts::*;
+pub use search::*;
+pub use sessions::*;
+pub use usage::*;
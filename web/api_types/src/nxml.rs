@@ -0,0 +1,95 @@
+// The text of the `<sec>` elements of an NXML document, each block prefixed
+// with the marker `chunk_meta` reads back: `title: ==`, `content: START`,
+// `table:`, `list:` and `reference: `. Used by the command line tools that
+// index the collection and by the server for uploaded documents.
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+
+pub fn nxml_sections(text: &str) -> Result<Vec<String>, quick_xml::Error> {
+    let mut reader = Reader::from_str(text);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut sections = Vec::new();
+    let mut sec_txt = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let tag = e.name().as_ref().to_vec();
+                if let b"title" | b"p" | b"table" | b"list" | b"ref" = tag.as_slice() {
+                    let sep = if let b"list" | b"ref" = tag.as_slice() {
+                        " "
+                    } else {
+                        ""
+                    };
+                    let text = remove_xml_tags(&reader.read_text(e.name())?, sep)?;
+                    sec_txt.push(match tag.as_slice() {
+                        b"title" => format!("title: == {} ==", text),
+                        b"p" => format!("content: START {} END", text),
+                        b"table" => format!("table:\n{}", text),
+                        b"list" => format!("list:\n{}", text),
+                        _ => format!("reference: {}", text),
+                    });
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"sec" => {
+                sections.push(sec_txt.join("\n"));
+                sec_txt.clear();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    if sections.is_empty() && !sec_txt.is_empty() {
+        // no <sec> element, the whole document is one section
+        sections.push(sec_txt.join("\n"));
+    }
+    Ok(sections)
+}
+
+fn remove_xml_tags(xml: &str, sep: &str) -> Result<String, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut txt = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Text(e) => txt.push(String::from_utf8_lossy(e.as_ref()).into_owned()),
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(txt.join(sep))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_meta::{chunk_types, section_title};
+
+    #[test]
+    fn marks_the_blocks_of_each_section() {
+        let nxml = "<book><sec><title>Summary</title>\
+                    <p>The NARS2 gene.</p>\
+                    <list><item>one</item><item>two</item></list></sec>\
+                    <sec><table><tr><td>a</td><td>b</td></tr></table>\
+                    <ref><name>Doe</name><year>2020</year></ref></sec></book>";
+        let sections = nxml_sections(nxml).unwrap();
+        assert_eq!(
+            sections,
+            vec![
+                "title: == Summary ==\ncontent: START The NARS2 gene. END\nlist:\none two",
+                "table:\nab\nreference: Doe 2020",
+            ]
+        );
+        assert_eq!(section_title(&sections[0]), Some("Summary".to_string()));
+        assert_eq!(chunk_types(&sections[1]), vec!["table", "reference"]);
+    }
+
+    #[test]
+    fn a_document_without_sections_is_one() {
+        let sections = nxml_sections("<book><p>only text</p></book>").unwrap();
+        assert_eq!(sections, vec!["content: START only text END"]);
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+// One version of a prompt, read from a `.toml` file of the prompts directory.
+// Changing a prompt means adding a file with a higher `version`, so answers
+// recorded with the old id can still be compared with the new ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: u32,
+    #[serde(default)]
+    pub description: String,
+    /// the placeholder of the user template the query text goes into
+    #[serde(default = "default_input")]
+    pub input: String,
+    pub system: String,
+    /// `{{context}}` (or `{{history}}` for follow-up rewriting) and `{{<input>}}`
+    pub user: String,
+}
+
+fn default_input() -> String {
+    "question".to_string()
+}
+
+impl PromptTemplate {
+    // `name@v<version>`, what requests pick and what answers record
+    pub fn id(&self) -> String {
+        format!("{}@v{}", self.name, self.version)
+    }
+
+    fn placeholder(name: &str) -> String {
+        format!("{{{{{}}}}}", name)
+    }
+
+    pub fn has_placeholder(&self, name: &str) -> bool {
+        self.user.contains(&Self::placeholder(name))
+    }
+
+    // fill the `{{name}}` placeholders of the user template
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        values.iter().fold(self.user.clone(), |prompt, (name, value)| {
+            prompt.replace(&Self::placeholder(name), value)
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DocumentRecord {
+    pub score: Option<f32>,
+    pub file_name: Option<String>,
+    pub url: Option<String>,
+    pub document_id: Option<usize>,
+    pub section_id: Option<usize>,
+    pub chunk_id: Option<usize>,
+    pub keywords: Option<String>,
+    pub text: Option<String>,
+    /// the embedding, only kept while diversifying
+    pub vec: Option<Vec<f32>>,
+    pub rerank_score: Option<f32>,
+    pub rerank_latency_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    Chunks,
+    #[default]
+    Sections,
+    /// documents -> sections -> chunks
+    Hierarchical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Diversity {
+    /// maximal marginal relevance trade-off, 1.0 is pure relevance and 0.0 pure novelty
+    pub mmr_lambda: Option<f32>,
+    /// keep at most this many hits from the same document
+    pub max_per_document: Option<usize>,
+    /// number of candidates fetched from the vector store before diversification
+    pub fetch_k: Option<u64>,
+}
+
+impl Diversity {
+    pub fn is_enabled(&self) -> bool {
+        self.mmr_lambda.is_some() || self.max_per_document.is_some()
+    }
+
+    pub fn candidate_limit(&self, topn: u64) -> u64 {
+        if self.is_enabled() {
+            self.fetch_k.unwrap_or(topn * 4).max(topn)
+        } else {
+            topn
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkType {
+    Paragraph,
+    Table,
+    List,
+    Reference,
+}
+
+impl ChunkType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChunkType::Paragraph => "paragraph",
+            ChunkType::Table => "table",
+            ChunkType::List => "list",
+            ChunkType::Reference => "reference",
+        }
+    }
+}
+
+impl std::str::FromStr for ChunkType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "paragraph" => Ok(ChunkType::Paragraph),
+            "table" => Ok(ChunkType::Table),
+            "list" => Ok(ChunkType::List),
+            "reference" => Ok(ChunkType::Reference),
+            _ => Err(format!("unknown chunk type {}", s)),
+        }
+    }
+}
+
+// Each non-empty list is an "any of" condition, the lists are combined with "and".
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default)]
+pub struct SearchFilters {
+    pub document_ids: Vec<usize>,
+    pub file_names: Vec<String>,
+    /// terms matched against the keyword groups in `keywords.jsonl`
+    pub keywords: Vec<String>,
+    /// matched against the `section_title` payload written by `create_qdrant_db`
+    pub section_titles: Vec<String>,
+    pub chunk_types: Vec<ChunkType>,
+}
+
+// Sampling settings a request can override, unset ones fall back to the
+// model config and then to the backend defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GenerationParams {
+    /// one of the `models` of the server config
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl GenerationParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(t) = self.temperature {
+            if !(0. ..=2.).contains(&t) {
+                return Err("temperature must be between 0 and 2".to_string());
+            }
+        }
+        if let Some(n) = self.max_tokens {
+            if n == 0 || n > 4096 {
+                return Err("max_tokens must be between 1 and 4096".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The body of the search, answer and summary endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueryText {
+    pub topn: u64,
+    pub text: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// number of documents kept at the first level of the hierarchical search
+    pub top_documents: Option<u64>,
+    /// number of sections kept at the second level of the hierarchical search
+    pub top_sections: Option<u64>,
+    /// over-fetch this many hits and rerank them with the cross-encoder
+    pub rerank_candidates: Option<u64>,
+    /// MMR / per-document cap applied to the first stage hits
+    #[serde(default)]
+    pub diversity: Diversity,
+    /// restrict the search to documents, keyword groups, section titles or chunk types
+    #[serde(default)]
+    pub filters: SearchFilters,
+    /// continue a conversation created with `POST /api/v1/sessions`
+    pub session_id: Option<String>,
+    /// model, temperature, max_tokens and seed of the answer
+    #[serde(flatten)]
+    pub generation: GenerationParams,
+    /// prompt template id, `name@vN` or a bare name for the latest version
+    pub prompt: Option<String>,
+}
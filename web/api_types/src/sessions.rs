@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct Turn {
+    pub question: String,
+    /// the follow-up rewritten into a question that stands on its own, used for retrieval
+    pub standalone_query: String,
+    pub answer: String,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Session {
+    pub session_id: String,
+    pub created_at: i64,
+    pub turns: Vec<Turn>,
+}
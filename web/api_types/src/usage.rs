@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+// 0 is no limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct KeyLimits {
+    pub requests_per_minute: u32,
+    /// prompt and completion tokens per day
+    pub daily_token_quota: u64,
+    /// in the unit of the model prices of the server config
+    pub daily_cost_quota: f64,
+}
+
+// What the calling key spent since 00:00 UTC, `GET /api/v1/usage`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct KeyUsage {
+    /// name of the API key, unset when the server doesn't require keys
+    pub api_key: Option<String>,
+    /// unix seconds, the start of the quota day
+    pub since: i64,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cost: f64,
+    pub limits: KeyLimits,
+}
@@ -1,30 +1,48 @@
 #![allow(non_snake_case)]
 // import the prelude to get access to the `rsx!` macro and the `Scope` and `Element` types
 use dioxus::prelude::*;
-use serde::{Deserialize, Serialize};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{stream, SinkExt, StreamExt};
+use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
+use serde::Serialize;
+use api_types::{ChatKind, ClientMessage, DocumentRecord, ErrorBody, Page, QueryText, ServerMessage};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct QueryText {
-    topn: u64,
-    text: String,
+fn error_text(err: ErrorBody) -> String {
+    let hint = if err.retryable { ", please try again" } else { "" };
+    format!("ERROR ({}): {}{}", err.code, err.message, hint)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DocumentRecord {
-    score: Option<f32>,
-    file_name: Option<String>,
-    url: Option<String>,
-    document_id: Option<usize>,
-    section_id: Option<usize>,
-    chunk_id: Option<usize>,
-    keywords: Option<String>,
-    text: Option<String>,
+fn error_record(message: String) -> DocumentRecord {
+    DocumentRecord {
+        text: Some(message),
+        ..Default::default()
+    }
 }
 
 pub fn base_url() -> String {
     web_sys::window().unwrap().location().origin().unwrap()
 }
 
+// the API key typed in the header, kept in the browser's local storage
+fn api_key() -> Option<String> {
+    let storage = web_sys::window()?.local_storage().ok()??;
+    storage.get_item("api_key").ok()?.filter(|k| !k.is_empty())
+}
+
+fn set_api_key(key: &str) {
+    if let Some(Ok(Some(storage))) = web_sys::window().map(|w| w.local_storage()) {
+        let _ = storage.set_item("api_key", key);
+    }
+}
+
+// only servers with `auth.enabled` need it
+fn with_api_key(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match api_key() {
+        Some(key) => request.header("x-api-key", key),
+        None => request,
+    }
+}
+
 fn main() {
     // launch the web app
     dioxus_web::launch(App);
@@ -35,6 +53,11 @@ fn main() {
 fn App(cx: Scope) -> Element {
     let diags = use_ref(cx,  Vec::<(String, Vec<DocumentRecord>)>::new);
     let current_input = use_state(cx, || "Tell me about Floating-Harbor Syndrome".to_string());
+    // follow-up questions are asked in the same server side session
+    let session = use_ref(cx, || None::<String>);
+    // stops the answer being generated
+    let stop = use_ref(cx, || None::<UnboundedSender<()>>);
+    let key_input = use_state(cx, || api_key().unwrap_or_default());
 
     cx.render(rsx! {
         div { class: "flex flex-col p-12 justify-center h-full",
@@ -42,6 +65,16 @@ fn App(cx: Scope) -> Element {
                 p { class: "mb-2 mt-0 text-3xl font-medium leading-tight text-primary",
                     "Ask Anything about GeneReviews"
                 }
+                input {
+                    class: "ml-4 px-3 py-1",
+                    r#type: "password",
+                    placeholder: "API key",
+                    value: "{key_input}",
+                    oninput: move |evt| {
+                        set_api_key(&evt.value);
+                        key_input.set(evt.value.clone());
+                    }
+                }
             }
 
             div { class: "flex flex-col flex-col-reverse h-full",
@@ -70,8 +103,7 @@ fn App(cx: Scope) -> Element {
                             class: "px-2 py-1 h-full middle none rounded-lg bg-blue-600 text-white",
                             onclick: move |_evt| {
                                 let input = current_input.get().clone();
-                                post_query(cx, "post_query_for_summary_of_a_topic", &input, diags);
-
+                                chat_query(cx, ChatKind::Summary, &input, diags, session, stop);
                             },
                             "Write a Summary "
                         }
@@ -81,11 +113,32 @@ fn App(cx: Scope) -> Element {
                             class: "px-2 py-1 h-full middle none rounded-lg bg-blue-600 text-white",
                             onclick: move |_evt| {
                                 let input = current_input.get().clone();
-                                post_query(cx, "post_query_for_answer_of_a_question", &input, diags);
+                                chat_query(cx, ChatKind::Question, &input, diags, session, stop);
                             },
                             "Get an Answer"
                         }
                     }
+                    div { class: "px-3 py-1",
+                        div {
+                            class: "px-2 py-1 h-full middle none rounded-lg bg-blue-600 text-white",
+                            onclick: move |_evt| {
+                                if let Some(stop) = stop.read().as_ref() {
+                                    let _ = stop.unbounded_send(());
+                                }
+                            },
+                            "Stop"
+                        }
+                    }
+                    div { class: "px-3 py-1",
+                        div {
+                            class: "px-2 py-1 h-full middle none rounded-lg bg-blue-600 text-white",
+                            onclick: move |_evt| {
+                                session.set(None);
+                                diags.write().clear();
+                            },
+                            "New Conversation"
+                        }
+                    }
                 }
                 div { class: "container h-full py-8 px-2 border-solid border-2 rounded-lg border-indigo-600 overscroll-contain overflow-auto",
                     Dialogs(cx, diags)
@@ -124,6 +177,18 @@ fn Dialogs<'a>(cx: Scope<'a>, diags: &'a UseRef<Vec<(String, Vec<DocumentRecord>
                                 rsx! {pre {"KEYWORD: {keywords}"}}
                             }
                             br {}
+                            if output.document_id.is_some() && output.section_id.is_some() {
+                                let document_id = output.document_id.unwrap();
+                                let section_id = output.section_id.unwrap();
+                                rsx! {
+                                    a {
+                                        class: "text-blue-600 underline cursor-pointer",
+                                        onclick: move |_evt| show_section(cx, document_id, section_id, diags),
+                                        "show full section"
+                                    }
+                                }
+                            }
+                            br {}
                             if output.text.is_some() {
                                 let text = output.text.clone().unwrap(); 
 
@@ -151,32 +216,224 @@ fn post_query<'a, T>(
     let query = QueryText {
         text: query.to_string(),
         topn: 3,
+        ..Default::default()
     };
     let records = records.to_owned();
     let entry = entry.to_owned();
     cx.spawn({
         async move {
             let client = reqwest::Client::new();
-            let url = base_url() + "/api/" + &entry;
-            let response = client
-                .post(url)
-                .json(&query)
-                .send()
-                .await
-                .unwrap()
-                .json::<Option<Vec<DocumentRecord>>>()
-                .await;
-            log::debug!("{:?}", response);
-            match response {
-                Ok(val) => {
-                    if let Some(val) = val {
-                        records.write().push((query.text, val));
-                    };
+            let url = base_url() + "/api/v1/" + &entry;
+            let response = with_api_key(client.post(url)).json(&query).send().await;
+            let output = match response {
+                Ok(response) if response.status().is_success() => {
+                    match response.json::<Vec<DocumentRecord>>().await {
+                        Ok(val) => val,
+                        Err(e) => vec![error_record(format!("ERROR: {}", e))],
+                    }
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    match response.json::<ErrorBody>().await {
+                        Ok(err) => vec![error_record(error_text(err))],
+                        Err(_) => vec![error_record(format!("ERROR: {}", status))],
+                    }
+                }
+                Err(e) => vec![error_record(format!("ERROR: {}", e))],
+            };
+            log::debug!("{:?}", output);
+            records.write().push((query.text, output));
+        }
+    })
+}
+
+// the ordered chunks of a search hit's section, added as a dialog of its own
+fn show_section<'a, T>(
+    cx: Scope<'a, T>,
+    document_id: usize,
+    section_id: usize,
+    records: &'a UseRef<Vec<(String, Vec<DocumentRecord>)>>,
+) {
+    let records = records.to_owned();
+    cx.spawn({
+        async move {
+            let url = format!(
+                "{}/api/v1/documents/{}/sections/{}/chunks?limit=500",
+                base_url(),
+                document_id,
+                section_id
+            );
+            let output = match with_api_key(reqwest::Client::new().get(url)).send().await {
+                Ok(response) if response.status().is_success() => {
+                    match response.json::<Page<DocumentRecord>>().await {
+                        Ok(page) => page.items,
+                        Err(e) => vec![error_record(format!("ERROR: {}", e))],
+                    }
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    match response.json::<ErrorBody>().await {
+                        Ok(err) => vec![error_record(error_text(err))],
+                        Err(_) => vec![error_record(format!("ERROR: {}", status))],
+                    }
+                }
+                Err(e) => vec![error_record(format!("ERROR: {}", e))],
+            };
+            let title = format!("section {} of document {}", section_id, document_id);
+            records.write().push((title, output));
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct SocketParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+}
+
+enum Incoming {
+    Server(Result<Message, WebSocketError>),
+    Stop,
+}
+
+// A WebSocket per question, in the session of the conversation: the answer is
+// the first record of the dialog and grows with every `token` message, the
+// `sources` records are listed after it. "Stop" sends `cancel`.
+fn chat_query<'a, T>(
+    cx: Scope<'a, T>,
+    kind: ChatKind,
+    query: &'a str,
+    records: &'a UseRef<Vec<(String, Vec<DocumentRecord>)>>,
+    session: &'a UseRef<Option<String>>,
+    stop: &'a UseRef<Option<UnboundedSender<()>>>,
+) {
+    let query = QueryText {
+        text: query.to_string(),
+        topn: 3,
+        ..Default::default()
+    };
+    let records = records.to_owned();
+    let session = session.to_owned();
+    let stop = stop.to_owned();
+    records
+        .write()
+        .push((query.text.clone(), vec![error_record(String::new())]));
+    let index = records.read().len() - 1;
+    cx.spawn({
+        async move {
+            // the dialog may have been cleared by "New Conversation" meanwhile
+            let set_answer = |text: String| {
+                if let Some((_, output)) = records.write().get_mut(index) {
+                    output[0].text = Some(text);
                 }
+            };
+            // the server creates a session on the first question
+            let params = SocketParams {
+                session_id: session.read().clone(),
+                api_key: api_key(),
+            };
+            let url = format!(
+                "{}/api/v1/ws?{}",
+                base_url().replacen("http", "ws", 1),
+                serde_qs::to_string(&params).unwrap_or_default()
+            );
+            let socket = match WebSocket::open(&url) {
+                Ok(socket) => socket,
                 Err(e) => {
-                    log::debug!("{:?}", e);
+                    set_answer(format!("ERROR: {}", e));
+                    return;
                 }
             };
+            let (mut write, read) = socket.split();
+            let message = ClientMessage::Query {
+                query: Box::new(query),
+                kind,
+            };
+            let text = serde_json::to_string(&message).expect("message serialization");
+            if let Err(e) = write.send(Message::Text(text)).await {
+                set_answer(format!("ERROR: {}", e));
+                return;
+            }
+            let (stop_tx, stop_rx) = mpsc::unbounded::<()>();
+            stop.set(Some(stop_tx));
+
+            let mut incoming = stream::select(
+                read.map(Incoming::Server),
+                stop_rx.map(|()| Incoming::Stop),
+            );
+            let mut answer = String::new();
+            while let Some(incoming) = incoming.next().await {
+                let text = match incoming {
+                    Incoming::Stop => {
+                        let cancel = serde_json::to_string(&ClientMessage::Cancel)
+                            .expect("message serialization");
+                        let _ = write.send(Message::Text(cancel)).await;
+                        continue;
+                    }
+                    Incoming::Server(Ok(Message::Text(text))) => text,
+                    Incoming::Server(Ok(_)) => continue,
+                    Incoming::Server(Err(e)) => {
+                        set_answer(format!("{}\nERROR: {}", answer, e));
+                        break;
+                    }
+                };
+                let message = match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::warn!("unexpected message {}: {}", text, e);
+                        continue;
+                    }
+                };
+                match message {
+                    ServerMessage::Session { session_id } => session.set(Some(session_id)),
+                    ServerMessage::Sources { records: mut sources } => {
+                        // numbered as in the prompt, so `[n]` in the answer points to them
+                        for (i, source) in sources.iter_mut().enumerate() {
+                            let text = source.text.take().unwrap_or_default();
+                            source.text = Some(format!("[{}] {}", i + 1, text));
+                        }
+                        if let Some((_, output)) = records.write().get_mut(index) {
+                            output.extend(sources);
+                        }
+                    }
+                    ServerMessage::Token { text } => {
+                        answer.push_str(&text);
+                        set_answer(answer.clone());
+                    }
+                    ServerMessage::Error { error } => {
+                        set_answer(error_text(error));
+                        break;
+                    }
+                    ServerMessage::Cancelled => {
+                        set_answer(format!("{}\n\n(stopped)", answer));
+                        break;
+                    }
+                    ServerMessage::Done { answer: done } => {
+                        log::debug!("done: {:?}", done);
+                        let mut notes = vec![];
+                        if !done.unknown_citations.is_empty() {
+                            let unknown = done
+                                .unknown_citations
+                                .iter()
+                                .map(|n| format!("[{}]", n))
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            notes.push(format!("WARNING: the answer cites unknown sources {}", unknown));
+                        }
+                        if done.cache_hit {
+                            notes.push("(answered from the cache)".to_string());
+                        }
+                        if !notes.is_empty() {
+                            set_answer(format!("{}\n\n{}", done.text, notes.join("\n")));
+                        }
+                        break;
+                    }
+                }
+            }
+            stop.set(None);
+            let _ = write.close().await;
         }
     })
 }
@@ -6,4 +6,4 @@ pushd frontend
 trunk build --release
 popd
 
-cargo run --bin server --release --  --addr 0.0.0.0 --port 3000 --static-dir ./dist
+cargo run --bin server --release --  --addr 0.0.0.0 --port 3000 --static-dir ./dist --config server.toml
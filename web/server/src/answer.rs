@@ -0,0 +1,205 @@
+use std::time::Instant;
+
+use api_types::{Answer, DocumentRecord, GenerationParams, PromptTemplate, Turn, Usage};
+use tokio::sync::mpsc;
+
+use crate::citations::check_citations;
+use crate::context::PackedContext;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::state::AppState;
+use crate::tokens::count_tokens;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AnswerKind {
+    Question,
+    Summary,
+}
+
+impl AnswerKind {
+    // the prompt template used when the request doesn't pick one
+    pub fn default_prompt<'a>(&self, state: &'a AppState) -> &'a str {
+        match self {
+            AnswerKind::Question => &state.config.question_prompt,
+            AnswerKind::Summary => &state.config.summary_prompt,
+        }
+    }
+}
+
+// The template a request asked for, or the default of its kind. It must have
+// a `{{context}}` for the retrieved sources.
+pub fn resolve_prompt<'a>(
+    state: &'a AppState,
+    kind: AnswerKind,
+    requested: Option<&str>,
+) -> Result<&'a PromptTemplate, ApiError> {
+    let id = requested.unwrap_or(kind.default_prompt(state));
+    let template = state
+        .prompts
+        .get(id)
+        .ok_or_else(|| ApiError::BadRequest(format!("unknown prompt template {}", id)))?;
+    if !template.has_placeholder("context") {
+        return Err(ApiError::BadRequest(format!(
+            "prompt template {} has no {{{{context}}}}",
+            template.id()
+        )));
+    }
+    Ok(template)
+}
+
+// the context block of the `n`th record, numbered from 1 so the model can
+// cite it as `[n]`
+pub fn format_block(n: usize, record: &DocumentRecord) -> String {
+    let mut out_strings = vec![format!("[{}]", n)];
+    if let Some(keywords) = &record.keywords {
+        out_strings.push(format!("KEYWORDS: {}", keywords))
+    };
+    if let Some(url) = &record.url {
+        out_strings.push(format!("URL: {}", url))
+    };
+    if let Some(text) = &record.text {
+        out_strings.push(format!("CONTENT: {}", text))
+    };
+    out_strings.join("\n")
+}
+
+pub fn format_context(records: &[DocumentRecord]) -> String {
+    records
+        .iter()
+        .enumerate()
+        .map(|(i, record)| format_block(i + 1, record))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub fn format_history(history: &[Turn]) -> String {
+    history
+        .iter()
+        .map(|turn| format!("USER: {}\nASSISTANT: {}", turn.question, turn.answer))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// the requested model, or the server default
+pub fn model_name<'a>(state: &'a AppState, params: &'a GenerationParams) -> &'a str {
+    params.model.as_deref().unwrap_or(&state.config.model)
+}
+
+// Rewrite a follow-up like "what about its inheritance?" into a question that
+// can be searched for without the conversation. The tokens are counted
+// against `api_key`, they are not part of the answer's usage.
+pub async fn condense_question(
+    state: &AppState,
+    params: &GenerationParams,
+    history: &[Turn],
+    question: &str,
+    api_key: Option<&str>,
+) -> Result<String, ApiError> {
+    if history.is_empty() {
+        return Ok(question.to_string());
+    }
+    let template = state
+        .prompts
+        .get(&state.config.condense_prompt)
+        .ok_or_else(|| ApiError::internal("the condense prompt template is missing"))?;
+    let prompt = template.render(&[
+        ("history", format_history(history).as_str()),
+        (template.input.as_str(), question),
+    ]);
+    let completion = state
+        .limits
+        .generate
+        .run(state.llms.complete(
+            model_name(state, params),
+            &template.system,
+            &[],
+            &prompt,
+            params,
+            None,
+        ))
+        .await?;
+    let model = completion.model.as_str();
+    let prompt_tokens = count_tokens(model, &template.system) + count_tokens(model, &prompt);
+    let completion_tokens = count_tokens(model, &completion.text);
+    state
+        .audit
+        .spend(api_key, "condense", model, prompt_tokens, completion_tokens)
+        .await;
+    let standalone = completion.text.trim();
+    if standalone.is_empty() {
+        Ok(question.to_string())
+    } else {
+        Ok(standalone.to_string())
+    }
+}
+
+// Run the requested chat model over the packed `context`, after the prior
+// turns of the conversation. With `tokens` set the completion is streamed and
+// every piece of text is forwarded as it arrives; generation stops early if
+// the receiver is gone.
+pub async fn generate_answer(
+    state: &AppState,
+    params: &GenerationParams,
+    template: &PromptTemplate,
+    query_text: &str,
+    packed: &PackedContext,
+    history: &[Turn],
+    tokens: Option<&mpsc::Sender<String>>,
+) -> Result<Answer, ApiError> {
+    let start = Instant::now();
+    let context = format_context(&packed.records);
+    if context.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "no context was found for the {}",
+            template.input
+        )));
+    }
+    let prompt_text =
+        template.render(&[("context", context.as_str()), (template.input.as_str(), query_text)]);
+
+    let model = model_name(state, params);
+    let completion = state
+        .limits
+        .generate
+        .run(state.llms.complete(
+            model,
+            &template.system,
+            history,
+            &prompt_text,
+            params,
+            tokens,
+        ))
+        .await?;
+    let text = completion.text;
+
+    let usage = Usage::new(
+        count_tokens(model, &template.system)
+            + count_tokens(model, &format_history(history))
+            + count_tokens(model, &prompt_text),
+        count_tokens(model, &text),
+        start.elapsed().as_millis() as u64,
+    );
+
+    metrics::count_tokens(
+        &completion.model,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+    );
+
+    let (sources, unknown_citations) = check_citations(&packed.records, &text);
+    if !unknown_citations.is_empty() {
+        tracing::warn!("the answer cites unknown sources {:?}", unknown_citations);
+    }
+
+    Ok(Answer {
+        text,
+        sources,
+        unknown_citations,
+        context: packed.report.clone(),
+        model: completion.model,
+        prompt: template.id(),
+        usage,
+        log_id: None,
+        cache_hit: false,
+    })
+}
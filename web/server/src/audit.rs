@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::Result;
+use api_types::{
+    Answer, DocumentRecord, ExportRange, Feedback, FeedbackRequest, LogRecord, RetrievedPoint,
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::error::ApiError;
+use crate::sessions::now_secs;
+
+// What a request did, filled in as it goes so that failed requests are
+// logged with whatever they got to.
+pub struct AuditEntry {
+    started: Instant,
+    endpoint: &'static str,
+    query: String,
+    session_id: Option<String>,
+    api_key: Option<String>,
+    standalone_query: Option<String>,
+    retrieved: Vec<RetrievedPoint>,
+    prompt: Option<String>,
+    model: Option<String>,
+    answer: Option<String>,
+    prompt_tokens: Option<usize>,
+    completion_tokens: Option<usize>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        endpoint: &'static str,
+        query: &str,
+        session_id: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Self {
+        Self {
+            started: Instant::now(),
+            endpoint,
+            query: query.to_string(),
+            session_id: session_id.map(|s| s.to_string()),
+            api_key: api_key.map(|k| k.to_string()),
+            standalone_query: None,
+            retrieved: vec![],
+            prompt: None,
+            model: None,
+            answer: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+
+    pub fn retrieved(&mut self, records: &[DocumentRecord]) {
+        self.retrieved = records.iter().map(RetrievedPoint::from).collect();
+    }
+
+    pub fn grounded(&mut self, standalone_query: &str, prompt: String, records: &[DocumentRecord]) {
+        self.standalone_query = Some(standalone_query.to_string());
+        self.prompt = Some(prompt);
+        self.retrieved(records);
+    }
+
+    pub fn api_key(&self) -> Option<&str> {
+        self.api_key.as_deref()
+    }
+
+    pub fn answered(&mut self, answer: &Answer) {
+        self.model = Some(answer.model.clone());
+        self.answer = Some(answer.text.clone());
+        self.prompt_tokens = Some(answer.usage.prompt_tokens);
+        self.completion_tokens = Some(answer.usage.completion_tokens);
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct LogRow {
+    log_id: i64,
+    created_at: i64,
+    endpoint: String,
+    query: String,
+    session_id: Option<String>,
+    standalone_query: Option<String>,
+    retrieved: String,
+    prompt: Option<String>,
+    model: Option<String>,
+    answer: Option<String>,
+    error: Option<String>,
+    latency_ms: i64,
+    prompt_tokens: Option<i64>,
+    completion_tokens: Option<i64>,
+    api_key: Option<String>,
+}
+
+// what one model was used for, in the quota accounting
+#[derive(sqlx::FromRow)]
+pub struct ModelUsage {
+    pub model: Option<String>,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+// Every search and answer request with what it retrieved and produced, and
+// the feedback users gave on the answers, in a SQLite file.
+pub struct AuditLog {
+    pool: SqlitePool,
+}
+
+impl AuditLog {
+    pub async fn open(path: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS queries (
+                log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at INTEGER NOT NULL,
+                endpoint TEXT NOT NULL,
+                query TEXT NOT NULL,
+                session_id TEXT,
+                standalone_query TEXT,
+                retrieved TEXT NOT NULL,
+                prompt TEXT,
+                model TEXT,
+                answer TEXT,
+                error TEXT,
+                latency_ms INTEGER NOT NULL,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        // logs written before requests carried API keys
+        let columns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('queries')")
+                .fetch_all(&pool)
+                .await?;
+        if !columns.iter().any(|(name,)| name == "api_key") {
+            sqlx::query("ALTER TABLE queries ADD COLUMN api_key TEXT")
+                .execute(&pool)
+                .await?;
+        }
+        sqlx::query("CREATE INDEX IF NOT EXISTS queries_by_key ON queries (api_key, created_at)")
+            .execute(&pool)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS feedback (
+                feedback_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                log_id INTEGER NOT NULL REFERENCES queries(log_id),
+                rating INTEGER NOT NULL,
+                comment TEXT,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        // the tokens spent besides the answers, counted in the quotas
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS spend (
+                spend_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at INTEGER NOT NULL,
+                api_key TEXT,
+                stage TEXT NOT NULL,
+                model TEXT NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS spend_by_key ON spend (api_key, created_at)")
+            .execute(&pool)
+            .await?;
+        Ok(Self { pool })
+    }
+
+    // The id of the new log entry. A failed write is only a warning, losing a
+    // log line is better than failing the request.
+    pub async fn record(&self, entry: AuditEntry, error: Option<&ApiError>) -> Option<i64> {
+        let retrieved = serde_json::to_string(&entry.retrieved).unwrap_or_default();
+        let inserted: Result<(i64,), sqlx::Error> = sqlx::query_as(
+            "INSERT INTO queries (created_at, endpoint, query, session_id, standalone_query,
+                retrieved, prompt, model, answer, error, latency_ms, prompt_tokens, completion_tokens,
+                api_key)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             RETURNING log_id",
+        )
+        .bind(now_secs())
+        .bind(entry.endpoint)
+        .bind(&entry.query)
+        .bind(&entry.session_id)
+        .bind(&entry.standalone_query)
+        .bind(retrieved)
+        .bind(&entry.prompt)
+        .bind(&entry.model)
+        .bind(&entry.answer)
+        .bind(error.map(|e| e.to_string()))
+        .bind(entry.started.elapsed().as_millis() as i64)
+        .bind(entry.prompt_tokens.map(|n| n as i64))
+        .bind(entry.completion_tokens.map(|n| n as i64))
+        .bind(&entry.api_key)
+        .fetch_one(&self.pool)
+        .await;
+        match inserted {
+            Ok((log_id,)) => Some(log_id),
+            Err(e) => {
+                tracing::warn!("audit log: {}", e);
+                None
+            }
+        }
+    }
+
+    // Tokens a key spent outside of the logged answer: condensing a follow-up,
+    // embedding a query or an upload. Like `record` a failed write is only a
+    // warning.
+    pub async fn spend(
+        &self,
+        api_key: Option<&str>,
+        stage: &str,
+        model: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    ) {
+        let inserted = sqlx::query(
+            "INSERT INTO spend (created_at, api_key, stage, model, prompt_tokens, completion_tokens)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(now_secs())
+        .bind(api_key)
+        .bind(stage)
+        .bind(model)
+        .bind(prompt_tokens as i64)
+        .bind(completion_tokens as i64)
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = inserted {
+            tracing::warn!("audit log: {}", e);
+        }
+    }
+
+    // feedback on a request of `api_key` when it is set, the requests of
+    // other keys are not found like in `export`
+    pub async fn add_feedback(
+        &self,
+        feedback: &FeedbackRequest,
+        api_key: Option<&str>,
+    ) -> Result<Feedback, ApiError> {
+        let logged: Option<(i64,)> = sqlx::query_as(
+            "SELECT log_id FROM queries WHERE log_id = ?1 AND (?2 IS NULL OR api_key = ?2)",
+        )
+        .bind(feedback.log_id)
+        .bind(api_key)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        if logged.is_none() {
+            return Err(ApiError::NotFound(format!(
+                "log entry {} not found",
+                feedback.log_id
+            )));
+        }
+        sqlx::query_as::<_, Feedback>(
+            "INSERT INTO feedback (log_id, rating, comment, created_at)
+             VALUES (?, ?, ?, ?)
+             RETURNING feedback_id, log_id, rating, comment, created_at",
+        )
+        .bind(feedback.log_id)
+        .bind(feedback.rating.value())
+        .bind(&feedback.comment)
+        .bind(now_secs())
+        .fetch_one(&self.pool)
+        .await
+        .map_err(ApiError::internal)
+    }
+
+    // the requests in `range`, only those of `api_key` when it is set
+    pub async fn export(
+        &self,
+        range: &ExportRange,
+        api_key: Option<&str>,
+    ) -> Result<Vec<LogRecord>, ApiError> {
+        let since = range.since.unwrap_or(i64::MIN);
+        let until = range.until.unwrap_or(i64::MAX);
+        let rows = sqlx::query_as::<_, LogRow>(
+            "SELECT * FROM queries WHERE created_at >= ?1 AND created_at < ?2
+             AND (?3 IS NULL OR api_key = ?3) ORDER BY log_id",
+        )
+        .bind(since)
+        .bind(until)
+        .bind(api_key)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        let feedback = sqlx::query_as::<_, Feedback>(
+            "SELECT feedback.* FROM feedback JOIN queries USING (log_id)
+             WHERE queries.created_at >= ?1 AND queries.created_at < ?2
+             AND (?3 IS NULL OR queries.api_key = ?3) ORDER BY feedback_id",
+        )
+        .bind(since)
+        .bind(until)
+        .bind(api_key)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        let mut feedback_by_log = HashMap::<i64, Vec<Feedback>>::new();
+        for f in feedback {
+            feedback_by_log.entry(f.log_id).or_default().push(f);
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|row| LogRecord {
+                log_id: row.log_id,
+                created_at: row.created_at,
+                endpoint: row.endpoint,
+                query: row.query,
+                session_id: row.session_id,
+                standalone_query: row.standalone_query,
+                retrieved: serde_json::from_str(&row.retrieved).unwrap_or_default(),
+                prompt: row.prompt,
+                model: row.model,
+                answer: row.answer,
+                error: row.error,
+                latency_ms: row.latency_ms,
+                prompt_tokens: row.prompt_tokens,
+                completion_tokens: row.completion_tokens,
+                api_key: row.api_key,
+                feedback: feedback_by_log.remove(&row.log_id).unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    // the requests and the other spending of a key since `since`, by model;
+    // `None` is the requests made without a key
+    pub async fn usage_since(
+        &self,
+        api_key: Option<&str>,
+        since: i64,
+    ) -> Result<Vec<ModelUsage>, ApiError> {
+        sqlx::query_as::<_, ModelUsage>(
+            "SELECT model, SUM(requests) AS requests,
+                COALESCE(SUM(prompt_tokens), 0) AS prompt_tokens,
+                COALESCE(SUM(completion_tokens), 0) AS completion_tokens
+             FROM (
+                SELECT model, 1 AS requests, prompt_tokens, completion_tokens
+                FROM queries WHERE api_key IS ?1 AND created_at >= ?2
+                UNION ALL
+                SELECT model, 0, prompt_tokens, completion_tokens
+                FROM spend WHERE api_key IS ?1 AND created_at >= ?2
+             )
+             GROUP BY model",
+        )
+        .bind(api_key)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(ApiError::internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api_types::Rating;
+
+    use super::*;
+
+    fn rating(log_id: i64) -> FeedbackRequest {
+        FeedbackRequest {
+            log_id,
+            rating: Rating::Up,
+            comment: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn feedback_only_on_the_requests_of_the_key() {
+        let path = std::env::temp_dir().join(format!("audit_test_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = AuditLog::open(path.to_str().unwrap()).await.unwrap();
+        let entry = AuditEntry::new(
+            "answer_of_a_question",
+            "What is NARS2?",
+            None,
+            Some("alice"),
+        );
+        let log_id = audit.record(entry, None).await.unwrap();
+
+        assert!(matches!(
+            audit.add_feedback(&rating(log_id), Some("bob")).await,
+            Err(ApiError::NotFound(_))
+        ));
+        let feedback = audit
+            .add_feedback(&rating(log_id), Some("alice"))
+            .await
+            .unwrap();
+        assert_eq!((feedback.log_id, feedback.rating), (log_id, 1));
+        // an admin, or a server without keys
+        assert!(audit.add_feedback(&rating(log_id), None).await.is_ok());
+        assert!(matches!(
+            audit.add_feedback(&rating(log_id + 1), None).await,
+            Err(ApiError::NotFound(_))
+        ));
+
+        let records = audit
+            .export(&ExportRange::default(), Some("alice"))
+            .await
+            .unwrap();
+        assert_eq!(records[0].feedback.len(), 2);
+        assert!(audit
+            .export(&ExportRange::default(), Some("bob"))
+            .await
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use api_types::{KeyLimits, KeyUsage};
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::config::{AuthConfig, ServerConfig};
+use crate::error::ApiError;
+use crate::sessions::now_secs;
+use crate::state::{AppState, SharedState};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub limits: KeyLimits,
+    pub admin: bool,
+}
+
+// Who made a request, put in the request extensions by `authenticate`; no
+// key when the server doesn't require one.
+#[derive(Debug, Clone)]
+pub struct Caller(pub Option<ApiKey>);
+
+impl Caller {
+    pub fn key_name(&self) -> Option<&str> {
+        self.0.as_ref().map(|k| k.name.as_str())
+    }
+
+    // without keys every caller is trusted, there is no one to tell apart
+    pub fn is_admin(&self) -> bool {
+        self.0.as_ref().map_or(true, |k| k.admin)
+    }
+
+    pub fn require_admin(&self) -> Result<(), ApiError> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden("this needs an admin API key".to_string()))
+        }
+    }
+}
+
+// keys are only kept hashed, in memory and in the key database
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// start of the quota day, 00:00 UTC
+fn day_start(now: i64) -> i64 {
+    now - now.rem_euclid(24 * 3600)
+}
+
+#[derive(sqlx::FromRow)]
+struct KeyRow {
+    name: String,
+    requests_per_minute: Option<i64>,
+    daily_token_quota: Option<i64>,
+    daily_cost_quota: Option<f64>,
+    admin: bool,
+}
+
+// The API keys of the config and of the key database, and the request
+// times of each key over the last minute.
+pub struct Auth {
+    config: AuthConfig,
+    config_keys: HashMap<String, ApiKey>,
+    pool: SqlitePool,
+    recent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Auth {
+    pub async fn open(config: &AuthConfig) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(&config.key_db)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(2)
+            .connect_with(options)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                name TEXT PRIMARY KEY,
+                key_hash TEXT NOT NULL UNIQUE,
+                requests_per_minute INTEGER,
+                daily_token_quota INTEGER,
+                daily_cost_quota REAL,
+                created_at INTEGER NOT NULL,
+                admin INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        // key databases created before admin keys
+        let columns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('api_keys')")
+                .fetch_all(&pool)
+                .await?;
+        if !columns.iter().any(|(name,)| name == "admin") {
+            sqlx::query("ALTER TABLE api_keys ADD COLUMN admin INTEGER NOT NULL DEFAULT 0")
+                .execute(&pool)
+                .await?;
+        }
+        let config_keys = config
+            .keys
+            .iter()
+            .map(|k| {
+                let key = ApiKey {
+                    name: k.name.clone(),
+                    limits: KeyLimits {
+                        requests_per_minute: k
+                            .requests_per_minute
+                            .unwrap_or(config.requests_per_minute),
+                        daily_token_quota: k.daily_token_quota.unwrap_or(config.daily_token_quota),
+                        daily_cost_quota: k.daily_cost_quota.unwrap_or(config.daily_cost_quota),
+                    },
+                    admin: k.admin,
+                };
+                (hash_key(&k.key), key)
+            })
+            .collect();
+        Ok(Self {
+            config: config.clone(),
+            config_keys,
+            pool,
+            recent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    fn default_limits(&self) -> KeyLimits {
+        KeyLimits {
+            requests_per_minute: self.config.requests_per_minute,
+            daily_token_quota: self.config.daily_token_quota,
+            daily_cost_quota: self.config.daily_cost_quota,
+        }
+    }
+
+    // a new random key stored under `name`, only its hash is kept so it is
+    // shown once
+    pub async fn add_key(&self, name: &str, admin: bool) -> Result<String> {
+        if self.config.keys.iter().any(|k| k.name == name) {
+            bail!("API key {} is already defined in the config", name);
+        }
+        let (key,): (String,) = sqlx::query_as("SELECT lower(hex(randomblob(24)))")
+            .fetch_one(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO api_keys (name, key_hash, created_at, admin) VALUES (?, ?, ?, ?)",
+        )
+        .bind(name)
+        .bind(hash_key(&key))
+        .bind(now_secs())
+        .bind(admin)
+        .execute(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    pub async fn revoke_key(&self, name: &str) -> Result<()> {
+        let deleted = sqlx::query("DELETE FROM api_keys WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        if deleted.rows_affected() == 0 {
+            bail!("no API key {} in {}", name, self.config.key_db);
+        }
+        Ok(())
+    }
+
+    async fn find(&self, key: &str) -> Result<Option<ApiKey>, ApiError> {
+        let hash = hash_key(key);
+        if let Some(key) = self.config_keys.get(&hash) {
+            return Ok(Some(key.clone()));
+        }
+        let row = sqlx::query_as::<_, KeyRow>(
+            "SELECT name, requests_per_minute, daily_token_quota, daily_cost_quota, admin
+             FROM api_keys WHERE key_hash = ?",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        let defaults = self.default_limits();
+        Ok(row.map(|row| ApiKey {
+            name: row.name,
+            limits: KeyLimits {
+                requests_per_minute: row
+                    .requests_per_minute
+                    .map_or(defaults.requests_per_minute, |n| n as u32),
+                daily_token_quota: row
+                    .daily_token_quota
+                    .map_or(defaults.daily_token_quota, |n| n as u64),
+                daily_cost_quota: row.daily_cost_quota.unwrap_or(defaults.daily_cost_quota),
+            },
+            admin: row.admin,
+        }))
+    }
+
+    // counts the request against the key's last minute
+    pub fn check_rate(&self, key: &ApiKey) -> Result<(), ApiError> {
+        let limit = key.limits.requests_per_minute as usize;
+        if limit == 0 {
+            return Ok(());
+        }
+        let mut recent = self.recent.lock().expect("rate limit lock");
+        let times = recent.entry(key.name.clone()).or_default();
+        count_request(times, limit, Instant::now())
+    }
+}
+
+// `times` are the requests of a key within the window before the last one
+fn count_request(
+    times: &mut VecDeque<Instant>,
+    limit: usize,
+    now: Instant,
+) -> Result<(), ApiError> {
+    while times
+        .front()
+        .is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW)
+    {
+        times.pop_front();
+    }
+    if times.len() >= limit {
+        let retry_after = RATE_WINDOW.saturating_sub(now.duration_since(times[0]));
+        return Err(ApiError::RateLimited(
+            format!("more than {} requests per minute", limit),
+            retry_after.as_secs().max(1),
+        ));
+    }
+    times.push_back(now);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct KeyParam {
+    api_key: String,
+}
+
+fn presented_key(request: &Request) -> Option<String> {
+    let headers = request.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let key = bearer.or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()));
+    if let Some(key) = key {
+        return Some(key.trim().to_string());
+    }
+    // browsers can't set headers on a WebSocket, `/ws?api_key=` then
+    if is_websocket(headers) {
+        let Query(param) = Query::<KeyParam>::try_from_uri(request.uri()).ok()?;
+        return Some(param.api_key);
+    }
+    None
+}
+
+fn is_websocket(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+// Middleware of the API routes: checks the key and the per-minute rate and
+// passes the `Caller` on to the handlers.
+pub async fn authenticate(
+    State(state): State<SharedState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let caller = if state.auth.enabled() {
+        let Some(key) = presented_key(&request) else {
+            return Err(ApiError::Unauthorized("an API key is required".to_string()));
+        };
+        let Some(key) = state.auth.find(&key).await? else {
+            return Err(ApiError::Unauthorized("unknown API key".to_string()));
+        };
+        state.auth.check_rate(&key)?;
+        Caller(Some(key))
+    } else {
+        Caller(None)
+    };
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+fn cost(config: &ServerConfig, model: Option<&str>, prompt: i64, completion: i64) -> f64 {
+    let per_1k = |price: Option<f64>, tokens: i64| price.unwrap_or(0.) * tokens as f64 / 1000.;
+    if model == Some(config.embedding.model.as_str()) {
+        return per_1k(config.embedding.cost_per_1k, prompt);
+    }
+    let Some(model) = model.and_then(|m| config.models.iter().find(|c| c.name == m)) else {
+        return 0.;
+    };
+    per_1k(model.prompt_cost_per_1k, prompt) + per_1k(model.completion_cost_per_1k, completion)
+}
+
+// what the caller spent today, from the request log
+pub async fn usage_today(state: &AppState, caller: &Caller) -> Result<KeyUsage, ApiError> {
+    let since = day_start(now_secs());
+    let mut usage = KeyUsage {
+        api_key: caller.key_name().map(|k| k.to_string()),
+        since,
+        requests: 0,
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        cost: 0.,
+        limits: caller
+            .0
+            .as_ref()
+            .map_or(state.auth.default_limits(), |k| k.limits),
+    };
+    for model in state.audit.usage_since(caller.key_name(), since).await? {
+        usage.requests += model.requests;
+        usage.prompt_tokens += model.prompt_tokens;
+        usage.completion_tokens += model.completion_tokens;
+        usage.cost += cost(
+            &state.config,
+            model.model.as_deref(),
+            model.prompt_tokens,
+            model.completion_tokens,
+        );
+    }
+    Ok(usage)
+}
+
+// Checked before the requests that call a model or the embeddings; a request
+// that starts under the quota may end a little over it.
+pub async fn check_quota(state: &AppState, caller: &Caller) -> Result<(), ApiError> {
+    let Some(key) = &caller.0 else {
+        return Ok(());
+    };
+    let limits = key.limits;
+    if limits.daily_token_quota == 0 && limits.daily_cost_quota == 0. {
+        return Ok(());
+    }
+    let usage = usage_today(state, caller).await?;
+    let tokens = (usage.prompt_tokens + usage.completion_tokens) as u64;
+    if limits.daily_token_quota > 0 && tokens >= limits.daily_token_quota {
+        return Err(ApiError::QuotaExceeded(format!(
+            "the daily quota of {} tokens is used up",
+            limits.daily_token_quota
+        )));
+    }
+    if limits.daily_cost_quota > 0. && usage.cost >= limits.daily_cost_quota {
+        return Err(ApiError::QuotaExceeded(format!(
+            "the daily cost quota of {} is used up",
+            limits.daily_cost_quota
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_requests_within_the_window() {
+        let start = Instant::now();
+        let mut times = VecDeque::new();
+        for i in 0..3 {
+            assert!(count_request(&mut times, 3, start + Duration::from_secs(i)).is_ok());
+        }
+        match count_request(&mut times, 3, start + Duration::from_secs(20)) {
+            Err(ApiError::RateLimited(_, retry_after)) => assert_eq!(retry_after, 40),
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+        // the refused request isn't counted
+        assert_eq!(times.len(), 3);
+    }
+
+    #[test]
+    fn old_requests_leave_the_window() {
+        let start = Instant::now();
+        let mut times = VecDeque::new();
+        for i in 0..3 {
+            assert!(count_request(&mut times, 3, start + Duration::from_secs(i)).is_ok());
+        }
+        // the first request is a minute old, the second one isn't yet
+        assert!(count_request(&mut times, 3, start + RATE_WINDOW).is_ok());
+        assert!(count_request(&mut times, 3, start + RATE_WINDOW).is_err());
+        assert!(count_request(&mut times, 3, start + RATE_WINDOW + Duration::from_secs(1)).is_ok());
+        assert_eq!(times.len(), 3);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+
+// What every request is answered with.
+pub struct Replies {
+    /// size of the embedding vectors
+    pub dim: usize,
+    /// text of every chat completion
+    pub reply: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingRequest {
+    model: String,
+    input: EmbeddingInput,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatRequest {
+    model: String,
+    #[serde(default)]
+    stream: bool,
+}
+
+// Hashed bag of words, normalized: texts sharing words get close vectors so
+// searches return something sensible, and the same text always gets the
+// same vector.
+fn embed(text: &str, dim: usize) -> Vec<f32> {
+    let mut v = vec![0_f32; dim];
+    for word in text.split_whitespace() {
+        let mut hasher = DefaultHasher::new();
+        word.to_lowercase().hash(&mut hasher);
+        let h = hasher.finish();
+        let sign = if h & 1 == 0 { 1. } else { -1. };
+        v[(h >> 1) as usize % dim] += sign;
+    }
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+async fn embeddings(
+    State(replies): State<Arc<Replies>>,
+    Json(req): Json<EmbeddingRequest>,
+) -> Json<Value> {
+    let inputs = match req.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    let data = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, text)| json!({"object": "embedding", "index": i, "embedding": embed(text, replies.dim)}))
+        .collect::<Vec<_>>();
+    Json(json!({
+        "object": "list",
+        "model": req.model,
+        "data": data,
+        "usage": {"prompt_tokens": 0, "total_tokens": 0},
+    }))
+}
+
+// any model name is accepted, the list only has to answer for `/readyz`
+async fn models() -> Json<Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{"id": "fake", "object": "model", "owned_by": "fake_openai"}],
+    }))
+}
+
+async fn chat_completions(
+    State(replies): State<Arc<Replies>>,
+    Json(req): Json<ChatRequest>,
+) -> Response {
+    if !req.stream {
+        return Json(json!({
+            "object": "chat.completion",
+            "model": req.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": replies.reply},
+                "finish_reason": "stop",
+            }],
+        }))
+        .into_response();
+    }
+
+    let model = req.model.clone();
+    let mut events = replies
+        .reply
+        .split_inclusive(' ')
+        .map(|piece| {
+            json!({
+                "object": "chat.completion.chunk",
+                "model": model,
+                "choices": [{"index": 0, "delta": {"content": piece}, "finish_reason": null}],
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    events.push("[DONE]".to_string());
+    let stream =
+        tokio_stream::iter(events).map(|data| Ok::<_, Infallible>(Event::default().data(data)));
+    Sse::new(stream).into_response()
+}
+
+// The OpenAI routes the server calls, under `/v1`.
+pub fn router(replies: Replies) -> Router {
+    Router::new()
+        .route("/v1/models", get(models))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(Arc::new(replies))
+}
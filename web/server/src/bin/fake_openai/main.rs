@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+
+mod app;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "fake_openai",
+    about = "OpenAI compatible stand-in answering canned embeddings and completions"
+)]
+struct Opt {
+    #[clap(short = 'p', long = "port", default_value = "8089")]
+    port: u16,
+
+    /// size of the embedding vectors, must match the Qdrant collections
+    #[clap(long = "dim", default_value = "1536")]
+    dim: usize,
+
+    /// text of every chat completion
+    #[clap(
+        long = "reply",
+        default_value = "This is a canned answer from the fake OpenAI server [1]."
+    )]
+    reply: String,
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::parse();
+    let addr = SocketAddr::from(([127, 0, 0, 1], opt.port));
+
+    let app = app::router(app::Replies {
+        dim: opt.dim,
+        reply: opt.reply,
+    });
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    println!("fake OpenAI server listening on http://{}/v1", addr);
+    axum::serve(listener, app).await.unwrap();
+}
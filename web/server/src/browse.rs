@@ -0,0 +1,114 @@
+use api_types::filters::{and, match_keyword, Level};
+use api_types::{DocumentInfo, DocumentRecord, Page, Pagination, SectionInfo};
+
+use crate::error::ApiError;
+use crate::query_qdrant_db::{
+    fetch_doc_sec, payload_string, payload_usize, record_from_payload, scroll_all,
+};
+use crate::state::AppState;
+
+type Result<T> = std::result::Result<T, ApiError>;
+
+// The collections hold one point per document and per section, small enough
+// to be scrolled whole and paged in memory; Qdrant's own scroll offsets are
+// point ids, which wouldn't give stable page numbers.
+pub async fn list_documents(
+    state: &AppState,
+    pagination: &Pagination,
+) -> Result<Page<DocumentInfo>> {
+    let mut documents = scroll_all(state, Level::Documents, None, false)
+        .await?
+        .into_iter()
+        .filter_map(|p| {
+            let file_name = payload_string(&p.payload, "file_name")?;
+            Some(DocumentInfo {
+                document_id: payload_usize(&p.payload, "document_id")?,
+                url: state.document_url(&file_name),
+                keywords: state.keywords(&file_name),
+                file_name,
+            })
+        })
+        .collect::<Vec<_>>();
+    documents.sort_by_key(|d| d.document_id);
+    Ok(pagination.page(documents))
+}
+
+pub async fn list_sections(
+    state: &AppState,
+    document_id: usize,
+    pagination: &Pagination,
+) -> Result<Page<SectionInfo>> {
+    let filter = and(
+        None,
+        vec![match_keyword("document_id", document_id.to_string())],
+    );
+    let mut sections = scroll_all(state, Level::Sections, filter, false)
+        .await?
+        .into_iter()
+        .filter_map(|p| {
+            Some(SectionInfo {
+                document_id,
+                section_id: payload_usize(&p.payload, "section_id")?,
+                section_title: payload_string(&p.payload, "section_title"),
+                chunk_types: p
+                    .payload
+                    .get("chunk_types")
+                    .and_then(|v| serde_json::to_value(v).ok())
+                    .and_then(|v| serde_json::from_value(v).ok())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    if sections.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "document {} not found",
+            document_id
+        )));
+    }
+    sections.sort_by_key(|s| s.section_id);
+    Ok(pagination.page(sections))
+}
+
+// the chunks of a section in order, without their vectors
+pub async fn section_chunks(
+    state: &AppState,
+    document_id: usize,
+    section_id: usize,
+    pagination: &Pagination,
+) -> Result<Page<DocumentRecord>> {
+    let mut chunks = fetch_doc_sec(state, document_id as u64, section_id as u64).await?;
+    if chunks.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "section {} of document {} not found",
+            section_id, document_id
+        )));
+    }
+    chunks.iter_mut().for_each(|c| c.vec = None);
+    Ok(pagination.page(chunks))
+}
+
+pub async fn fetch_chunk(
+    state: &AppState,
+    document_id: usize,
+    section_id: usize,
+    chunk_id: usize,
+) -> Result<DocumentRecord> {
+    let filter = and(
+        None,
+        vec![
+            match_keyword("document_id", document_id.to_string()),
+            match_keyword("section_id", section_id.to_string()),
+            match_keyword("chunk_id", chunk_id.to_string()),
+        ],
+    );
+    scroll_all(state, Level::Chunks, filter, false)
+        .await?
+        .first()
+        .map(|p| record_from_payload(state, &p.payload))
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "chunk {} of section {} of document {} not found",
+                chunk_id, section_id, document_id
+            ))
+        })
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use api_types::filters::Level;
+use api_types::{Answer, DocumentRecord, QueryText, Usage};
+
+use crate::answer::AnswerKind;
+use crate::config::CacheConfig;
+use crate::diversify::cosine;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::state::{AppState, SharedState};
+
+// Where an answer goes in the cache: everything but the question text that
+// shapes the answer, and the question itself.
+pub struct CacheKey {
+    scope: String,
+    query: String,
+    vector: Option<Vec<f32>>,
+}
+
+// an answer as it was generated, with the records of its context
+#[derive(Clone)]
+pub struct CachedAnswer {
+    pub answer: Answer,
+    pub records: Vec<DocumentRecord>,
+}
+
+impl CachedAnswer {
+    // what the client gets, nothing was spent on it
+    pub fn served(&self, start: Instant) -> Answer {
+        Answer {
+            usage: Usage::new(0, 0, start.elapsed().as_millis() as u64),
+            log_id: None,
+            cache_hit: true,
+            ..self.answer.clone()
+        }
+    }
+}
+
+struct Entry {
+    key: CacheKey,
+    cached: CachedAnswer,
+    stored: Instant,
+}
+
+// lower case, single spaces, no trailing punctuation
+fn normalise(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .trim_end_matches(|c: char| c.is_ascii_punctuation())
+        .to_string()
+}
+
+// Answers of the answer and summary endpoints kept in memory, oldest first.
+// Only requests without prior turns are cached, a follow-up depends on the
+// conversation.
+pub struct AnswerCache {
+    config: CacheConfig,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl AnswerCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            config: config.clone(),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn key(&self, kind: AnswerKind, model: &str, prompt: &str, query: &QueryText) -> CacheKey {
+        // the search and generation settings, the text, session and the
+        // resolved model and prompt are keyed on separately
+        let mut options = query.clone();
+        options.text = String::new();
+        options.session_id = None;
+        options.prompt = None;
+        options.generation.model = None;
+        CacheKey {
+            scope: format!(
+                "{:?}|{}|{}|{}",
+                kind,
+                model,
+                prompt,
+                serde_json::to_string(&options).unwrap_or_default()
+            ),
+            query: normalise(&query.text),
+            vector: None,
+        }
+    }
+
+    // the same question first, then the most similar one above the threshold
+    // when `key` has a vector
+    fn get(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        let mut entries = self.entries.lock().expect("cache lock");
+        entries.retain(|e| e.stored.elapsed() < ttl);
+        let in_scope = || entries.iter().filter(|e| e.key.scope == key.scope);
+        if let Some(entry) = in_scope().find(|e| e.key.query == key.query) {
+            return Some(entry.cached.clone());
+        }
+        let (vector, threshold) = (key.vector.as_ref()?, self.config.similarity_threshold?);
+        in_scope()
+            .filter_map(|e| Some((e, cosine(vector, e.key.vector.as_ref()?))))
+            .filter(|(_, similarity)| *similarity >= threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(e, _)| e.cached.clone())
+    }
+
+    pub fn insert(&self, key: CacheKey, answer: &Answer, records: &[DocumentRecord]) {
+        let mut entries = self.entries.lock().expect("cache lock");
+        entries.retain(|e| !(e.key.scope == key.scope && e.key.query == key.query));
+        while entries.len() >= self.config.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            key,
+            cached: CachedAnswer {
+                answer: answer.clone(),
+                records: records.to_vec(),
+            },
+            stored: Instant::now(),
+        });
+    }
+
+    pub fn clear(&self, reason: &str) {
+        let mut entries = self.entries.lock().expect("cache lock");
+        if !entries.is_empty() {
+            tracing::info!("clearing {} cached answers: {}", entries.len(), reason);
+            entries.clear();
+        }
+    }
+}
+
+// The cached answer for `key`. With a similarity threshold the query is
+// embedded on an exact miss, and the vector kept in `key` for the insert.
+pub async fn lookup(
+    state: &AppState,
+    key: &mut CacheKey,
+    text: &str,
+    api_key: Option<&str>,
+) -> Result<Option<CachedAnswer>, ApiError> {
+    let mut cached = state.cache.get(key);
+    if cached.is_none() && state.cache.config.similarity_threshold.is_some() {
+        key.vector = Some(state.embed(text, api_key).await?);
+        cached = state.cache.get(key);
+    }
+    metrics::count_cache_lookup(cached.is_some());
+    Ok(cached)
+}
+
+// Re-indexing with the command line tools changes the point counts of the
+// collections, the answers found in the old ones are dropped then.
+pub async fn watch_index(state: SharedState) {
+    let every = state.cache.config.index_check_secs;
+    if !state.cache.enabled() || every == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(every));
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        let mut counts = vec![];
+        for level in [Level::Documents, Level::Sections, Level::Chunks] {
+            let collection = state.collection(level);
+            match state.client.collection_info(&collection).await {
+                Ok(info) => counts.push(info.result.map(|i| i.points_count)),
+                Err(e) => tracing::warn!("cache: can't read {}: {}", collection, e),
+            }
+        }
+        if counts.len() < 3 {
+            continue;
+        }
+        if last.as_ref().is_some_and(|last| *last != counts) {
+            state.cache.clear("the collections changed");
+        }
+        last = Some(counts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api_types::ContextReport;
+
+    use super::*;
+
+    fn cache(ttl_secs: u64, similarity_threshold: Option<f32>) -> AnswerCache {
+        AnswerCache::new(&CacheConfig {
+            ttl_secs,
+            similarity_threshold,
+            ..Default::default()
+        })
+    }
+
+    fn query(text: &str, topn: u64) -> QueryText {
+        QueryText {
+            text: text.to_string(),
+            topn,
+            ..Default::default()
+        }
+    }
+
+    fn answer(text: &str) -> Answer {
+        Answer {
+            text: text.to_string(),
+            sources: vec![],
+            unknown_citations: vec![],
+            context: ContextReport::default(),
+            model: "gpt-3.5-turbo".to_string(),
+            prompt: "question_answering@v1".to_string(),
+            usage: Usage::new(10, 5, 100),
+            log_id: Some(1),
+            cache_hit: false,
+        }
+    }
+
+    fn key(cache: &AnswerCache, query: &QueryText) -> CacheKey {
+        let prompt = "question_answering@v1";
+        cache.key(AnswerKind::Question, "gpt-3.5-turbo", prompt, query)
+    }
+
+    fn cached_text(cache: &AnswerCache, key: &CacheKey) -> Option<String> {
+        cache.get(key).map(|c| c.answer.text)
+    }
+
+    #[test]
+    fn normalises_case_spacing_and_punctuation() {
+        assert_eq!(normalise("  What is  CFTR?? "), "what is cftr");
+        assert_eq!(normalise("What is CFTR"), normalise("what is\ncftr."));
+    }
+
+    #[test]
+    fn looks_up_within_the_scope() {
+        let cache = cache(60, None);
+        cache.insert(key(&cache, &query("What is CFTR?", 5)), &answer("a"), &[]);
+
+        assert_eq!(
+            cached_text(&cache, &key(&cache, &query("what is cftr", 5))),
+            Some("a".to_string())
+        );
+        // other search settings or another model make another answer
+        let other_topn = key(&cache, &query("what is cftr", 3));
+        assert_eq!(cached_text(&cache, &other_topn), None);
+        let other_model = cache.key(
+            AnswerKind::Question,
+            "gpt-4",
+            "question_answering@v1",
+            &query("what is cftr", 5),
+        );
+        assert_eq!(cached_text(&cache, &other_model), None);
+
+        let served = cache.get(&key(&cache, &query("what is cftr", 5))).unwrap();
+        let served = served.served(Instant::now());
+        assert!(served.cache_hit);
+        assert_eq!((served.usage.total_tokens, served.log_id), (0, None));
+    }
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let cache = cache(0, None);
+        cache.insert(key(&cache, &query("what is cftr", 5)), &answer("a"), &[]);
+        let same = key(&cache, &query("what is cftr", 5));
+        assert_eq!(cached_text(&cache, &same), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn serves_a_similar_question_above_the_threshold() {
+        let cache = cache(60, Some(0.9));
+        let mut stored = key(&cache, &query("what is cftr", 5));
+        stored.vector = Some(vec![1.0, 0.0]);
+        cache.insert(stored, &answer("a"), &[]);
+
+        let mut close = key(&cache, &query("what does cftr do", 5));
+        close.vector = Some(vec![0.95, 0.05]);
+        assert_eq!(cached_text(&cache, &close), Some("a".to_string()));
+        let mut far = key(&cache, &query("what is brca1", 5));
+        far.vector = Some(vec![0.0, 1.0]);
+        assert_eq!(cached_text(&cache, &far), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use api_types::{Answer, ChatKind, ClientMessage, QueryText, ServerMessage};
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::answer::AnswerKind;
+use crate::audit::AuditEntry;
+use crate::auth::{self, Caller};
+use crate::error::ApiError;
+use crate::state::{AppState, SharedState};
+use crate::{final_message, stream_answer, validate};
+
+struct Running {
+    task: JoinHandle<()>,
+    cancel: Arc<Notify>,
+}
+
+impl Running {
+    fn busy(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+// A conversation over `/api/v1/ws`: `session` first, then for each query the
+// messages of the streaming endpoints, or `cancelled` when the client stops it.
+// The messages of a connection go through one writer task.
+pub async fn run(socket: WebSocket, state: SharedState, caller: Caller, session_id: String) {
+    let (mut sink, mut stream) = socket.split();
+    let (out, mut out_rx) = mpsc::channel::<ServerMessage>(64);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            let text = serde_json::to_string(&message).expect("message serialization");
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+    let session = ServerMessage::Session {
+        session_id: session_id.clone(),
+    };
+    let _ = out.send(session).await;
+
+    let mut running: Option<Running> = None;
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // pings are answered by axum
+            _ => continue,
+        };
+        let busy = running.as_ref().is_some_and(Running::busy);
+        match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Query { mut query, kind }) if !busy => {
+                query.session_id = Some(session_id.clone());
+                let cancel = Arc::new(Notify::new());
+                let task = tokio::spawn(answer(
+                    state.clone(),
+                    caller.clone(),
+                    *query,
+                    kind,
+                    out.clone(),
+                    cancel.clone(),
+                ));
+                running = Some(Running { task, cancel });
+            }
+            Ok(ClientMessage::Query { .. }) => {
+                let error = ApiError::BadRequest("a query is already running".to_string());
+                let _ = out
+                    .send(ServerMessage::Error {
+                        error: error.body(),
+                    })
+                    .await;
+            }
+            Ok(ClientMessage::Cancel) => {
+                if let Some(running) = running.as_ref().filter(|r| r.busy()) {
+                    running.cancel.notify_one();
+                }
+            }
+            Err(e) => {
+                let error = ApiError::BadRequest(format!("invalid message: {}", e));
+                let _ = out
+                    .send(ServerMessage::Error {
+                        error: error.body(),
+                    })
+                    .await;
+            }
+        }
+    }
+
+    // nobody is left to read the answer
+    if let Some(running) = running {
+        running.cancel.notify_one();
+        let _ = running.task.await;
+    }
+    drop(out);
+    let _ = writer.await;
+}
+
+async fn answer(
+    state: SharedState,
+    caller: Caller,
+    query: QueryText,
+    kind: ChatKind,
+    out: mpsc::Sender<ServerMessage>,
+    cancel: Arc<Notify>,
+) {
+    let (kind, endpoint) = match kind {
+        ChatKind::Question => (AnswerKind::Question, "ws_answer_of_a_question"),
+        ChatKind::Summary => (AnswerKind::Summary, "ws_summary_of_a_topic"),
+    };
+    let mut entry = AuditEntry::new(
+        endpoint,
+        &query.text,
+        query.session_id.as_deref(),
+        caller.key_name(),
+    );
+    // a channel of the query's own: dropping it on cancel stops the model at
+    // its next token and keeps stray tokens off the connection
+    let (events, mut events_rx) = mpsc::channel::<ServerMessage>(64);
+    let forward_out = out.clone();
+    let forward = async move {
+        while let Some(message) = events_rx.recv().await {
+            if forward_out.send(message).await.is_err() {
+                break;
+            }
+        }
+    };
+    let result = tokio::select! {
+        (result, ()) = async {
+            tokio::join!(checked_answer(&state, &caller, &query, kind, &mut entry, events), forward)
+        } => result,
+        _ = cancel.notified() => Err(ApiError::Cancelled("stopped by the client".to_string())),
+    };
+    let log_id = state.audit.record(entry, result.as_ref().err()).await;
+    let _ = out.send(final_message(result, log_id)).await;
+}
+
+// each query counts against the rate limit and the quota like a request
+async fn checked_answer(
+    state: &AppState,
+    caller: &Caller,
+    query: &QueryText,
+    kind: AnswerKind,
+    entry: &mut AuditEntry,
+    events: mpsc::Sender<ServerMessage>,
+) -> Result<Answer, ApiError> {
+    validate(query)?;
+    if let Some(key) = &caller.0 {
+        state.auth.check_rate(key)?;
+    }
+    auth::check_quota(state, caller).await?;
+    stream_answer(state, query, kind, entry, &events).await
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeSet;
+
+use api_types::{DocumentRecord, Source};
+
+// The numbers inside `[1]`, `[1, 3]` or `[2-4]` markers, in order of first use.
+// Brackets holding anything else, e.g. `[citation needed]`, are skipped.
+pub fn parse_citations(text: &str) -> Vec<usize> {
+    let mut seen = BTreeSet::new();
+    let mut citations = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        let inner = &rest[..end];
+        if !inner.is_empty()
+            && inner
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == ',' || c == '-' || c == ' ')
+        {
+            for part in inner.split(',') {
+                let numbers = match part.trim().split_once('-') {
+                    Some((from, to)) => match (from.trim().parse::<usize>(), to.trim().parse::<usize>()) {
+                        (Ok(from), Ok(to)) if from <= to && to - from < 100 => {
+                            (from..=to).collect::<Vec<_>>()
+                        }
+                        _ => vec![],
+                    },
+                    None => part.trim().parse::<usize>().into_iter().collect::<Vec<_>>(),
+                };
+                for n in numbers {
+                    if seen.insert(n) {
+                        citations.push(n);
+                    }
+                }
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    citations
+}
+
+// Map the `[n]` markers of `answer` back to the numbered `records`; the
+// markers without a matching record are returned separately.
+pub fn check_citations(records: &[DocumentRecord], answer: &str) -> (Vec<Source>, Vec<usize>) {
+    let cited = parse_citations(answer);
+    let sources = records
+        .iter()
+        .enumerate()
+        .map(|(i, r)| Source {
+            index: i + 1,
+            url: r.url.clone(),
+            file_name: r.file_name.clone(),
+            document_id: r.document_id,
+            section_id: r.section_id,
+            chunk_id: r.chunk_id,
+            score: r.score,
+            rerank_score: r.rerank_score,
+            cited: cited.contains(&(i + 1)),
+        })
+        .collect::<Vec<_>>();
+    let unknown = cited
+        .into_iter()
+        .filter(|n| *n == 0 || *n > records.len())
+        .collect();
+    (sources, unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lists_and_ranges() {
+        assert_eq!(parse_citations("as shown [1,2] and [2]"), vec![1, 2]);
+        assert_eq!(parse_citations("see [3-5] then [1]"), vec![3, 4, 5, 1]);
+        assert_eq!(parse_citations("[4, 2 - 3]"), vec![4, 2, 3]);
+    }
+
+    #[test]
+    fn skips_other_brackets() {
+        assert_eq!(parse_citations("[citation needed] [] [5-2] [x1]"), vec![]);
+        assert_eq!(parse_citations("unclosed [1"), vec![]);
+    }
+
+    #[test]
+    fn flags_unknown_indices() {
+        let records = vec![DocumentRecord::default(), DocumentRecord::default()];
+        let (sources, unknown) = check_citations(&records, "[2] and [0], [3]");
+        assert_eq!(unknown, vec![0, 3]);
+        let cited = sources.iter().map(|s| (s.index, s.cited)).collect::<Vec<_>>();
+        assert_eq!(cited, vec![(1, false), (2, true)]);
+    }
+}
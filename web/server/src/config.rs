@@ -0,0 +1,795 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+const ENV_PREFIX: &str = "LLM_PLAYGROUND_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// an OpenAI compatible chat completions API
+    #[default]
+    Openai,
+    /// quantized Mistral on the CPU, with `local.offline` nothing leaves the
+    /// machine
+    Local,
+    /// canned deterministic answers, for tests
+    Mock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// what `model` in the config and in requests refers to
+    pub name: String,
+    pub backend: Backend,
+    /// the model id sent to the backend, `name` when unset
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+    /// `openai` backend: API root, `openai_base_url` when unset
+    pub base_url: Option<String>,
+    /// `openai` backend: environment variable holding the API key, `OPENAI_API_KEY` when unset
+    pub api_key_env: Option<String>,
+    /// price of 1000 prompt tokens, counted against `auth.daily_cost_quota`
+    pub prompt_cost_per_1k: Option<f64>,
+    pub completion_cost_per_1k: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    /// must be the model the collections were built with
+    pub model: String,
+    /// API root, `openai_base_url` when unset
+    pub base_url: Option<String>,
+    /// environment variable holding the API key
+    pub api_key_env: String,
+    /// price of 1000 embedded tokens, counted against `auth.daily_cost_quota`
+    pub cost_per_1k: Option<f64>,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            model: "text-embedding-ada-002".to_string(),
+            base_url: None,
+            api_key_env: "OPENAI_API_KEY".to_string(),
+            cost_per_1k: None,
+        }
+    }
+}
+
+// localhost, 127.0.0.0/8 or ::1
+fn is_loopback(url: &str) -> bool {
+    let Some(host) = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+    else {
+        return false;
+    };
+    host == "localhost"
+        || host == "[::1]"
+        || host
+            .parse::<std::net::Ipv4Addr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+impl ModelConfig {
+    pub fn model_id(&self) -> &str {
+        self.model.as_deref().unwrap_or(&self.name)
+    }
+}
+
+fn default_models() -> Vec<ModelConfig> {
+    vec![
+        ModelConfig {
+            name: "gpt-3.5-turbo".to_string(),
+            backend: Backend::Openai,
+            model: None,
+            temperature: None,
+            max_tokens: None,
+            base_url: None,
+            api_key_env: None,
+            prompt_cost_per_1k: None,
+            completion_cost_per_1k: None,
+        },
+        ModelConfig {
+            name: "mistral-7b-instruct".to_string(),
+            backend: Backend::Local,
+            model: None,
+            temperature: None,
+            max_tokens: None,
+            base_url: None,
+            api_key_env: None,
+            prompt_cost_per_1k: None,
+            completion_cost_per_1k: None,
+        },
+        ModelConfig {
+            name: "mock".to_string(),
+            backend: Backend::Mock,
+            model: None,
+            temperature: None,
+            max_tokens: None,
+            base_url: None,
+            api_key_env: None,
+            prompt_cost_per_1k: None,
+            completion_cost_per_1k: None,
+        },
+    ]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalLlmConfig {
+    /// Hugging Face repo holding `tokenizer.json` and the GGUF weights
+    pub repo: String,
+    pub file: String,
+    pub temperature: f64,
+    /// nucleus sampling, unset samples from the whole distribution
+    pub top_p: Option<f64>,
+    /// 1.0 disables the penalty
+    pub repeat_penalty: f32,
+    /// how many of the last tokens the repeat penalty looks at
+    pub repeat_last_n: usize,
+    pub max_tokens: usize,
+    pub seed: u64,
+    /// keep the questions to local models on this machine: the embeddings
+    /// must come from a loopback `embedding.base_url` and local models only
+    /// fall back to local models
+    pub offline: bool,
+    /// generation stops at the end of sequence token or at any of these
+    pub stop: Vec<String>,
+}
+
+impl Default for LocalLlmConfig {
+    fn default() -> Self {
+        Self {
+            repo: "lmz/candle-mistral".to_string(),
+            file: "model-q4k.gguf".to_string(),
+            temperature: 0.2,
+            top_p: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            max_tokens: 512,
+            seed: 299792458,
+            offline: false,
+            stop: vec!["[INST]".to_string()],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// what the logs and `/api/usage` call the key
+    pub name: String,
+    /// sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`
+    pub key: String,
+    /// the limits of `[auth]` unless set here
+    pub requests_per_minute: Option<u32>,
+    pub daily_token_quota: Option<u64>,
+    pub daily_cost_quota: Option<f64>,
+    /// may export every key's logs, clear the answer cache, upload documents
+    /// and cancel ingestion jobs
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// require an API key on every `/api` route but `/api/openapi.json`
+    pub enabled: bool,
+    pub keys: Vec<ApiKeyConfig>,
+    /// SQLite file of the keys created with `--add-api-key`, relative to the
+    /// config file
+    pub key_db: String,
+    /// limits of each key, 0 for none; the quotas reset at 00:00 UTC
+    pub requests_per_minute: u32,
+    pub daily_token_quota: u64,
+    pub daily_cost_quota: f64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            keys: vec![],
+            key_db: "api_keys.db".to_string(),
+            requests_per_minute: 60,
+            daily_token_quota: 0,
+            daily_cost_quota: 0.,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// serve repeated questions from memory, for requests without prior turns
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+    /// also serve the answer of a question whose embedding is at least this
+    /// cosine similar, unset for exact matches of the normalised text only
+    pub similarity_threshold: Option<f32>,
+    /// how often the point counts of the collections are checked, the cache
+    /// is cleared when they change; 0 disables the check
+    pub index_check_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 3600,
+            max_entries: 1000,
+            similarity_threshold: None,
+            index_check_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// how long in-flight requests get to finish on SIGTERM or SIGINT
+    pub shutdown_grace_secs: u64,
+    /// a stage slower than its timeout fails the request with 504, waiting
+    /// for a free slot included
+    pub embed_timeout_secs: u64,
+    pub search_timeout_secs: u64,
+    pub rerank_timeout_secs: u64,
+    /// the whole generation with its fallbacks, `llm_timeout_secs` bounds
+    /// each model
+    pub generate_timeout_secs: u64,
+    /// calls of a stage running at once, the others wait for a slot
+    pub max_concurrent_embeddings: usize,
+    pub max_concurrent_searches: usize,
+    pub max_concurrent_reranks: usize,
+    pub max_concurrent_generations: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_grace_secs: 30,
+            embed_timeout_secs: 15,
+            search_timeout_secs: 15,
+            rerank_timeout_secs: 60,
+            generate_timeout_secs: 180,
+            max_concurrent_embeddings: 16,
+            max_concurrent_searches: 32,
+            max_concurrent_reranks: 2,
+            max_concurrent_generations: 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub qdrant_url: String,
+    pub qdrant_timeout_secs: u64,
+    /// the collections are `{prefix}_documents`, `{prefix}_sections` and `{prefix}_chunks`
+    pub collection_prefix: String,
+    /// relative paths are resolved against the directory of the config file
+    pub keywords_path: String,
+    /// chat model used by the answer and summary endpoints unless the request
+    /// picks another one, one of `models`
+    pub model: String,
+    pub models: Vec<ModelConfig>,
+    /// tried in order when the model errors or doesn't answer in time
+    pub fallback_models: Vec<String>,
+    pub llm_timeout_secs: u64,
+    /// API root of the `openai` models and of the embeddings, point it to a
+    /// self-hosted OpenAI compatible server (llama.cpp, vLLM) or a test stand-in
+    pub openai_base_url: String,
+    pub embedding: EmbeddingConfig,
+    /// prompt size limit, counted with the tokenizer of `model`; the context is
+    /// packed into what the system prompt, history, template and question leave
+    pub max_prompt_tokens: usize,
+    pub reranker_model: String,
+    /// `{prefix}` is replaced by the file name without its extension
+    pub document_url_template: String,
+    /// directory of the `.toml` prompt templates, relative to the config file
+    pub prompts_dir: String,
+    /// template of the answer endpoints unless the request picks one, a
+    /// `name@vN` id or a bare name for the latest version
+    pub question_prompt: String,
+    /// template of the summary endpoints
+    pub summary_prompt: String,
+    /// rewrites a follow-up into a standalone query, uses `{{history}}`
+    pub condense_prompt: String,
+    /// SQLite file holding the conversation sessions, relative to the config file
+    pub session_db: String,
+    /// number of prior turns included in the prompt of a follow-up
+    pub history_turns: usize,
+    /// SQLite file of the query and answer log and of the feedback, relative
+    /// to the config file
+    pub audit_db: String,
+    /// size limit of `POST /api/documents` uploads
+    pub max_upload_mb: usize,
+    /// uploads waiting for the ingestion worker, more are refused with 503
+    pub max_queued_jobs: usize,
+    /// origins allowed to call the API from a browser, `*` for any; the
+    /// frontend served by the server itself needs none
+    pub cors_origins: Vec<String>,
+    pub auth: AuthConfig,
+    /// answers of the answer and summary endpoints
+    pub cache: CacheConfig,
+    /// timeouts and concurrency of the stages of a request, and shutdown
+    pub limits: LimitsConfig,
+    /// the `local` backend
+    pub local: LocalLlmConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            qdrant_url: "http://localhost:6334".to_string(),
+            qdrant_timeout_secs: 100,
+            collection_prefix: "NBK1116".to_string(),
+            keywords_path: "../test_doc/keywords.jsonl".to_string(),
+            model: "gpt-3.5-turbo".to_string(),
+            models: default_models(),
+            fallback_models: vec![],
+            llm_timeout_secs: 60,
+            openai_base_url: "https://api.openai.com/v1".to_string(),
+            embedding: EmbeddingConfig::default(),
+            max_prompt_tokens: 3000,
+            reranker_model: "cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+            document_url_template: "https://www.ncbi.nlm.nih.gov/books/n/gene/{prefix}"
+                .to_string(),
+            prompts_dir: "prompts".to_string(),
+            question_prompt: "question_answering@v1".to_string(),
+            summary_prompt: "topic_essay@v1".to_string(),
+            condense_prompt: "condense_question@v1".to_string(),
+            session_db: "sessions.db".to_string(),
+            history_turns: 4,
+            audit_db: "audit.db".to_string(),
+            max_upload_mb: 20,
+            max_queued_jobs: 8,
+            cors_origins: vec![],
+            auth: AuthConfig::default(),
+            cache: CacheConfig::default(),
+            limits: LimitsConfig::default(),
+            local: LocalLlmConfig::default(),
+        }
+    }
+}
+
+fn env_override(name: &str, value: &mut String) {
+    if let Ok(v) = std::env::var(format!("{}{}", ENV_PREFIX, name)) {
+        *value = v;
+    }
+}
+
+impl ServerConfig {
+    // defaults < config file < `LLM_PLAYGROUND_*` environment variables
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("can't read the config file {}", path))?;
+                let mut config: ServerConfig = toml::from_str(&text)
+                    .with_context(|| format!("can't parse the config file {}", path))?;
+                let base = Path::new(path).parent().unwrap_or(Path::new("."));
+                for file in [
+                    &mut config.keywords_path,
+                    &mut config.session_db,
+                    &mut config.audit_db,
+                    &mut config.auth.key_db,
+                    &mut config.prompts_dir,
+                ] {
+                    if Path::new(file.as_str()).is_relative() {
+                        *file = base.join(file.as_str()).to_string_lossy().to_string();
+                    }
+                }
+                config
+            }
+            None => ServerConfig::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_override("QDRANT_URL", &mut self.qdrant_url);
+        env_override("COLLECTION_PREFIX", &mut self.collection_prefix);
+        env_override("KEYWORDS_PATH", &mut self.keywords_path);
+        env_override("MODEL", &mut self.model);
+        env_override("OPENAI_BASE_URL", &mut self.openai_base_url);
+        env_override("EMBEDDING_MODEL", &mut self.embedding.model);
+        env_override("RERANKER_MODEL", &mut self.reranker_model);
+        env_override("DOCUMENT_URL_TEMPLATE", &mut self.document_url_template);
+        env_override("PROMPTS_DIR", &mut self.prompts_dir);
+        env_override("QUESTION_PROMPT", &mut self.question_prompt);
+        env_override("SUMMARY_PROMPT", &mut self.summary_prompt);
+        env_override("CONDENSE_PROMPT", &mut self.condense_prompt);
+        env_override("SESSION_DB", &mut self.session_db);
+        env_override("AUDIT_DB", &mut self.audit_db);
+        env_override("AUTH_KEY_DB", &mut self.auth.key_db);
+        if let Ok(v) = std::env::var(format!("{}AUTH_ENABLED", ENV_PREFIX)) {
+            self.auth.enabled = v
+                .parse()
+                .with_context(|| format!("{}AUTH_ENABLED must be true or false", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}CACHE_ENABLED", ENV_PREFIX)) {
+            self.cache.enabled = v
+                .parse()
+                .with_context(|| format!("{}CACHE_ENABLED must be true or false", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}CACHE_TTL_SECS", ENV_PREFIX)) {
+            self.cache.ttl_secs = v
+                .parse()
+                .with_context(|| format!("{}CACHE_TTL_SECS is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}CACHE_SIMILARITY_THRESHOLD", ENV_PREFIX)) {
+            self.cache.similarity_threshold = Some(v.parse().with_context(|| {
+                format!("{}CACHE_SIMILARITY_THRESHOLD is not a number", ENV_PREFIX)
+            })?);
+        }
+        if let Ok(v) = std::env::var(format!("{}CORS_ORIGINS", ENV_PREFIX)) {
+            self.cors_origins = v
+                .split(',')
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .collect();
+        }
+        if let Ok(v) = std::env::var(format!("{}QDRANT_TIMEOUT_SECS", ENV_PREFIX)) {
+            self.qdrant_timeout_secs = v
+                .parse()
+                .with_context(|| format!("{}QDRANT_TIMEOUT_SECS is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}FALLBACK_MODELS", ENV_PREFIX)) {
+            self.fallback_models = v
+                .split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect();
+        }
+        if let Ok(v) = std::env::var(format!("{}LLM_TIMEOUT_SECS", ENV_PREFIX)) {
+            self.llm_timeout_secs = v
+                .parse()
+                .with_context(|| format!("{}LLM_TIMEOUT_SECS is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}SHUTDOWN_GRACE_SECS", ENV_PREFIX)) {
+            self.limits.shutdown_grace_secs = v
+                .parse()
+                .with_context(|| format!("{}SHUTDOWN_GRACE_SECS is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}MAX_CONCURRENT_GENERATIONS", ENV_PREFIX)) {
+            self.limits.max_concurrent_generations = v.parse().with_context(|| {
+                format!("{}MAX_CONCURRENT_GENERATIONS is not a number", ENV_PREFIX)
+            })?;
+        }
+        if let Ok(v) = std::env::var(format!("{}MAX_PROMPT_TOKENS", ENV_PREFIX)) {
+            self.max_prompt_tokens = v
+                .parse()
+                .with_context(|| format!("{}MAX_PROMPT_TOKENS is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}MAX_UPLOAD_MB", ENV_PREFIX)) {
+            self.max_upload_mb = v
+                .parse()
+                .with_context(|| format!("{}MAX_UPLOAD_MB is not a number", ENV_PREFIX))?;
+        }
+        if let Ok(v) = std::env::var(format!("{}HISTORY_TURNS", ENV_PREFIX)) {
+            self.history_turns = v
+                .parse()
+                .with_context(|| format!("{}HISTORY_TURNS is not a number", ENV_PREFIX))?;
+        }
+        Ok(())
+    }
+
+    pub fn embedding_base_url(&self) -> &str {
+        self.embedding
+            .base_url
+            .as_deref()
+            .unwrap_or(&self.openai_base_url)
+    }
+
+    // whether embedding a query keeps it on this machine
+    pub fn embeddings_are_local(&self) -> bool {
+        is_loopback(self.embedding_base_url())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(self.qdrant_url.starts_with("http://") || self.qdrant_url.starts_with("https://")) {
+            bail!("qdrant_url must be an http(s) url, got {}", self.qdrant_url);
+        }
+        if self.qdrant_timeout_secs == 0 {
+            bail!("qdrant_timeout_secs must be positive");
+        }
+        if self.collection_prefix.trim().is_empty() {
+            bail!("collection_prefix must not be empty");
+        }
+        if !Path::new(&self.keywords_path).is_file() {
+            bail!("keywords_path {} is not a file", self.keywords_path);
+        }
+        let mut names = std::collections::HashSet::new();
+        for model in &self.models {
+            if !names.insert(model.name.as_str()) {
+                bail!("model {} is defined twice", model.name);
+            }
+        }
+        for name in std::iter::once(&self.model).chain(&self.fallback_models) {
+            if !names.contains(name.as_str()) {
+                bail!("model {} is not one of the configured models", name);
+            }
+        }
+        if self.llm_timeout_secs == 0 {
+            bail!("llm_timeout_secs must be positive");
+        }
+        let base_urls = std::iter::once(&self.openai_base_url)
+            .chain(self.embedding.base_url.iter())
+            .chain(self.models.iter().filter_map(|m| m.base_url.as_ref()));
+        for url in base_urls {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                bail!("base urls must be http(s) urls, got {}", url);
+            }
+        }
+        if self.max_prompt_tokens == 0 {
+            bail!("max_prompt_tokens must be positive");
+        }
+        if self.local.max_tokens == 0 {
+            bail!("local.max_tokens must be positive");
+        }
+        if self.local.temperature < 0. {
+            bail!("local.temperature must not be negative");
+        }
+        if self.local.offline && !self.embeddings_are_local() {
+            bail!(
+                "local.offline needs a loopback embedding.base_url, the queries would be sent to {}",
+                self.embedding_base_url()
+            );
+        }
+        if self.session_db.trim().is_empty() {
+            bail!("session_db must not be empty");
+        }
+        if self.audit_db.trim().is_empty() {
+            bail!("audit_db must not be empty");
+        }
+        if self.max_upload_mb == 0 {
+            bail!("max_upload_mb must be positive");
+        }
+        if self.max_queued_jobs == 0 {
+            bail!("max_queued_jobs must be positive");
+        }
+        if self.cache.enabled && (self.cache.ttl_secs == 0 || self.cache.max_entries == 0) {
+            bail!("cache.ttl_secs and cache.max_entries must be positive");
+        }
+        if let Some(threshold) = self.cache.similarity_threshold {
+            if !(threshold > 0. && threshold <= 1.) {
+                bail!("cache.similarity_threshold must be in (0, 1], got {}", threshold);
+            }
+        }
+        let limits = &self.limits;
+        let timeouts = [
+            limits.embed_timeout_secs,
+            limits.search_timeout_secs,
+            limits.rerank_timeout_secs,
+            limits.generate_timeout_secs,
+        ];
+        if timeouts.contains(&0) {
+            bail!("the limits.*_timeout_secs must be positive");
+        }
+        let concurrency = [
+            limits.max_concurrent_embeddings,
+            limits.max_concurrent_searches,
+            limits.max_concurrent_reranks,
+            limits.max_concurrent_generations,
+        ];
+        if concurrency.contains(&0) {
+            bail!("the limits.max_concurrent_* must be positive");
+        }
+        for origin in &self.cors_origins {
+            if !(origin == "*" || origin.starts_with("http://") || origin.starts_with("https://")) {
+                bail!("cors_origins must be http(s) origins or *, got {}", origin);
+            }
+        }
+        if self.auth.key_db.trim().is_empty() {
+            bail!("auth.key_db must not be empty");
+        }
+        let mut key_names = std::collections::HashSet::new();
+        for key in &self.auth.keys {
+            if !key_names.insert(key.name.as_str()) {
+                bail!("API key {} is defined twice", key.name);
+            }
+            if key.key.len() < 16 {
+                bail!("API key {} must be at least 16 characters", key.name);
+            }
+        }
+        let cost_quotas = std::iter::once(self.auth.daily_cost_quota)
+            .chain(self.auth.keys.iter().filter_map(|k| k.daily_cost_quota));
+        for quota in cost_quotas {
+            if quota < 0. {
+                bail!("daily_cost_quota must not be negative");
+            }
+        }
+        if !self.document_url_template.contains("{prefix}") {
+            bail!("document_url_template must contain {{prefix}}");
+        }
+        // the templates themselves are checked when they are loaded
+        if !Path::new(&self.prompts_dir).is_dir() {
+            bail!("prompts_dir {} is not a directory", self.prompts_dir);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // the tests reading `LLM_PLAYGROUND_*` take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn manifest_path(path: &str) -> String {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // the defaults, with files that exist
+    fn config() -> ServerConfig {
+        ServerConfig {
+            keywords_path: manifest_path("Cargo.toml"),
+            prompts_dir: manifest_path("../prompts"),
+            ..Default::default()
+        }
+    }
+
+    fn error(config: &ServerConfig) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        config().validate().unwrap();
+    }
+
+    #[test]
+    fn models_must_be_configured_once() {
+        let unknown = ServerConfig {
+            model: "gpt-5".to_string(),
+            ..config()
+        };
+        assert!(error(&unknown).contains("model gpt-5 is not one"));
+        let unknown_fallback = ServerConfig {
+            fallback_models: vec!["mock".to_string(), "claude".to_string()],
+            ..config()
+        };
+        assert!(error(&unknown_fallback).contains("model claude is not one"));
+
+        let mut twice = config();
+        twice.models.push(twice.models[0].clone());
+        assert!(error(&twice).contains("defined twice"));
+    }
+
+    #[test]
+    fn offline_needs_loopback_embeddings() {
+        let mut config = config();
+        config.local.offline = true;
+        assert!(error(&config).contains("api.openai.com"));
+        for url in [
+            "http://127.0.0.1:8089/v1",
+            "http://localhost:8080",
+            "http://[::1]:80",
+        ] {
+            config.embedding.base_url = Some(url.to_string());
+            config.validate().unwrap();
+        }
+        config.embedding.base_url = Some("http://10.0.0.2:8080".to_string());
+        assert!(config.validate().is_err());
+        // the embeddings fall back to `openai_base_url`
+        config.embedding.base_url = None;
+        config.openai_base_url = "http://127.0.0.1:8089/v1".to_string();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let mut cases = vec![];
+        let mut c = config();
+        c.cache.similarity_threshold = Some(1.5);
+        cases.push((c, "similarity_threshold"));
+        let mut c = config();
+        c.cors_origins = vec!["example.org".to_string()];
+        cases.push((c, "cors_origins"));
+        let mut c = config();
+        c.limits.rerank_timeout_secs = 0;
+        cases.push((c, "_timeout_secs"));
+        let mut c = config();
+        c.document_url_template = "https://example.org/books".to_string();
+        cases.push((c, "{prefix}"));
+        let mut c = config();
+        c.auth.keys = vec![ApiKeyConfig {
+            name: "ci".to_string(),
+            key: "short".to_string(),
+            requests_per_minute: None,
+            daily_token_quota: None,
+            daily_cost_quota: None,
+            admin: false,
+        }];
+        cases.push((c, "at least 16 characters"));
+        let mut c = config();
+        c.keywords_path = manifest_path("missing.jsonl");
+        cases.push((c, "is not a file"));
+        for (config, message) in cases {
+            let error = error(&config);
+            assert!(error.contains(message), "{} in {}", message, error);
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_config() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let vars = [
+            ("MODEL", "mock"),
+            ("FALLBACK_MODELS", "mock, gpt-3.5-turbo,"),
+            ("CACHE_SIMILARITY_THRESHOLD", "0.9"),
+            ("AUTH_ENABLED", "true"),
+            ("MAX_UPLOAD_MB", "5"),
+        ];
+        for (name, value) in vars {
+            std::env::set_var(format!("{}{}", ENV_PREFIX, name), value);
+        }
+        let mut config = config();
+        let applied = config.apply_env();
+        std::env::set_var(format!("{}AUTH_ENABLED", ENV_PREFIX), "yes");
+        let invalid = ServerConfig::default().apply_env();
+        for (name, _) in vars {
+            std::env::remove_var(format!("{}{}", ENV_PREFIX, name));
+        }
+
+        applied.unwrap();
+        assert_eq!(config.model, "mock");
+        assert_eq!(config.fallback_models, vec!["mock", "gpt-3.5-turbo"]);
+        assert_eq!(config.cache.similarity_threshold, Some(0.9));
+        assert!(config.auth.enabled);
+        assert_eq!(config.max_upload_mb, 5);
+        let error = invalid.unwrap_err().to_string();
+        assert!(error.contains("AUTH_ENABLED must be"), "{}", error);
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_config_file() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("config_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("keywords.jsonl"), "").unwrap();
+        let path = dir.join("server.toml");
+        let prompts = manifest_path("../prompts");
+        let text = format!(
+            "model = \"mock\"\n\
+             keywords_path = \"keywords.jsonl\"\n\
+             prompts_dir = {:?}\n\
+             [cache]\n\
+             ttl_secs = 10\n",
+            prompts
+        );
+        std::fs::write(&path, text).unwrap();
+        let loaded = ServerConfig::load(path.to_str());
+        std::fs::write(&path, "modle = \"mock\"\n").unwrap();
+        let misspelt = ServerConfig::load(path.to_str());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let config = loaded.unwrap();
+        let in_dir = |file: &str| dir.join(file).to_string_lossy().to_string();
+        assert_eq!(config.keywords_path, in_dir("keywords.jsonl"));
+        assert_eq!(config.session_db, in_dir("sessions.db"));
+        assert_eq!(config.auth.key_db, in_dir("api_keys.db"));
+        assert_eq!(config.prompts_dir, prompts);
+        // the rest of a table keeps its defaults
+        assert_eq!(config.cache.ttl_secs, 10);
+        assert_eq!(config.cache.max_entries, 1000);
+        assert!(format!("{:#}", misspelt.unwrap_err()).contains("unknown field `modle`"));
+    }
+}
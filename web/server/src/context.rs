@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use api_types::{BlockReport, ContextReport, DocumentRecord, PackReason, PromptTemplate, Turn};
+
+use crate::answer::{format_block, format_history};
+use crate::error::ApiError;
+use crate::tokens::{count_tokens, truncate_to_tokens};
+
+// a block is only trimmed if at least this much of it still fits
+const MIN_TRIMMED_TOKENS: usize = 64;
+
+// shorter shared runs of text are taken as chance, not as chunk overlap
+const MIN_OVERLAP_CHARS: usize = 32;
+
+pub struct PackedContext {
+    /// the blocks of the prompt, in order, numbered from 1
+    pub records: Vec<DocumentRecord>,
+    pub report: ContextReport,
+}
+
+fn relevance(record: &DocumentRecord) -> f32 {
+    record.rerank_score.or(record.score).unwrap_or(f32::MIN)
+}
+
+fn block_report(record: &DocumentRecord, tokens: usize, reason: PackReason) -> BlockReport {
+    BlockReport {
+        document_id: record.document_id,
+        section_id: record.section_id,
+        chunk_id: record.chunk_id,
+        score: record.rerank_score.or(record.score),
+        tokens,
+        reason,
+    }
+}
+
+fn is_duplicate(record: &DocumentRecord, kept: &[DocumentRecord]) -> bool {
+    let text = record.text.as_deref().unwrap_or_default().trim();
+    kept.iter().any(|k| {
+        let same_section =
+            k.document_id == record.document_id && k.section_id == record.section_id;
+        // a whole section covers its own chunks
+        (same_section && (k.chunk_id == record.chunk_id || k.chunk_id.is_none()))
+            || (!text.is_empty() && k.text.as_deref().unwrap_or_default().contains(text))
+    })
+}
+
+fn body(record: &DocumentRecord) -> &str {
+    record.text.as_deref().unwrap_or_default().trim_matches('"')
+}
+
+// the length of the longest suffix of `a` that is a prefix of `b`
+fn overlap(a: &str, b: &str) -> usize {
+    (1..=a.len().min(b.len()))
+        .rev()
+        .filter(|n| a.is_char_boundary(a.len() - n) && b.is_char_boundary(*n))
+        .find(|n| a[a.len() - n..] == b[..*n])
+        .unwrap_or(0)
+}
+
+// Where a chunk goes in a kept chunk of the same section covering chunks
+// `span`: right after it or right before it, by chunk id or by overlapping
+// text.
+fn joins(kept: &DocumentRecord, span: (usize, usize), record: &DocumentRecord) -> Option<bool> {
+    let chunk_id = record.chunk_id?;
+    kept.chunk_id?;
+    if kept.document_id != record.document_id || kept.section_id != record.section_id {
+        return None;
+    }
+    if chunk_id == span.1 + 1 || overlap(body(kept), body(record)) >= MIN_OVERLAP_CHARS {
+        Some(true)
+    } else if chunk_id + 1 == span.0 || overlap(body(record), body(kept)) >= MIN_OVERLAP_CHARS {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// the kept block with the text of `record` after or before its own, the
+// text they share written once
+fn merge(kept: &DocumentRecord, record: &DocumentRecord, after: bool) -> DocumentRecord {
+    let (a, b) = if after {
+        (body(kept), body(record))
+    } else {
+        (body(record), body(kept))
+    };
+    let shared = overlap(a, b);
+    let text = if shared >= MIN_OVERLAP_CHARS {
+        format!("{}{}", a, &b[shared..])
+    } else {
+        format!("{} {}", a, b)
+    };
+    DocumentRecord {
+        text: Some(text),
+        ..kept.clone()
+    }
+}
+
+// Fit the retrieved records into what is left of `max_prompt_tokens`, counted
+// with the tokenizer of `model`: the most relevant blocks go first, duplicates
+// are skipped, a chunk next to or overlapping a kept chunk of its section is
+// merged into it, the first block that doesn't fit is trimmed if enough of it
+// fits, and the rest is dropped. Overflow is only ever trimmed or dropped,
+// never summarised.
+pub fn pack_context(
+    max_prompt_tokens: usize,
+    model: &str,
+    template: &PromptTemplate,
+    query_text: &str,
+    history: &[Turn],
+    mut records: Vec<DocumentRecord>,
+) -> Result<PackedContext, ApiError> {
+    let prompt = template.render(&[("context", ""), (template.input.as_str(), query_text)]);
+    let fixed = count_tokens(model, &template.system)
+        + count_tokens(model, &format_history(history))
+        + count_tokens(model, &prompt);
+    let budget = max_prompt_tokens.saturating_sub(fixed);
+    if budget == 0 {
+        return Err(ApiError::BadRequest(format!(
+            "the question and conversation history alone exceed {} tokens",
+            max_prompt_tokens
+        )));
+    }
+
+    records.sort_by(|a, b| relevance(b).total_cmp(&relevance(a)));
+
+    let mut report = ContextReport {
+        budget,
+        ..Default::default()
+    };
+    let mut kept = Vec::<DocumentRecord>::new();
+    // the first and last chunk ids merged into each kept block
+    let mut spans = Vec::<(usize, usize)>::new();
+    let mut seen = HashSet::new();
+    for mut record in records {
+        // separator between blocks
+        let separator = if kept.is_empty() { 0 } else { 1 };
+        let block = format_block(kept.len() + 1, &record);
+        let tokens = count_tokens(model, &block) + separator;
+        let key = (record.document_id, record.section_id, record.chunk_id);
+        if !seen.insert(key) || is_duplicate(&record, &kept) {
+            report
+                .dropped
+                .push(block_report(&record, tokens, PackReason::Duplicate));
+            continue;
+        }
+
+        let remaining = budget - report.used_tokens;
+        let joined = kept
+            .iter()
+            .zip(&spans)
+            .enumerate()
+            .find_map(|(i, (k, span))| Some((i, joins(k, *span, &record)?)));
+        if let Some((i, after)) = joined {
+            let merged = merge(&kept[i], &record, after);
+            let before = count_tokens(model, &format_block(i + 1, &kept[i]));
+            let extra = count_tokens(model, &format_block(i + 1, &merged)).saturating_sub(before);
+            if extra <= remaining {
+                let chunk_id = record.chunk_id.unwrap_or_default();
+                let span = &mut spans[i];
+                *span = (span.0.min(chunk_id), span.1.max(chunk_id));
+                kept[i] = DocumentRecord {
+                    chunk_id: Some(span.0),
+                    ..merged
+                };
+                report.used_tokens += extra;
+                report
+                    .merged
+                    .push(block_report(&record, extra, PackReason::Merged));
+            } else {
+                report
+                    .dropped
+                    .push(block_report(&record, tokens, PackReason::OverBudget));
+            }
+            continue;
+        }
+
+        let chunk_id = record.chunk_id.unwrap_or_default();
+        if tokens <= remaining {
+            report.used_tokens += tokens;
+            kept.push(record);
+            spans.push((chunk_id, chunk_id));
+            continue;
+        }
+
+        // everything but the text has to fit as well
+        let text = record.text.take().unwrap_or_default();
+        let overhead = count_tokens(model, &format_block(kept.len() + 1, &record)) + separator;
+        if remaining >= overhead + MIN_TRIMMED_TOKENS {
+            let trimmed = truncate_to_tokens(model, &text, remaining - overhead);
+            record.text = Some(trimmed);
+            let used = count_tokens(model, &format_block(kept.len() + 1, &record)) + separator;
+            report.used_tokens += used.min(remaining);
+            report
+                .trimmed
+                .push(block_report(&record, tokens, PackReason::Trimmed));
+            kept.push(record);
+            spans.push((chunk_id, chunk_id));
+        } else {
+            record.text = Some(text);
+            report
+                .dropped
+                .push(block_report(&record, tokens, PackReason::OverBudget));
+        }
+    }
+
+    if !report.dropped.is_empty() || !report.trimmed.is_empty() {
+        tracing::info!(
+            "context packed into {}/{} tokens, {} merged, {} trimmed, {} dropped",
+            report.used_tokens,
+            budget,
+            report.merged.len(),
+            report.trimmed.len(),
+            report.dropped.len()
+        );
+    }
+
+    Ok(PackedContext {
+        records: kept,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "gpt-3.5-turbo";
+
+    fn template() -> PromptTemplate {
+        PromptTemplate {
+            name: "test".to_string(),
+            version: 1,
+            description: String::new(),
+            input: "question".to_string(),
+            system: "Answer from the context.".to_string(),
+            user: "{{context}}\n\n{{question}}".to_string(),
+        }
+    }
+
+    fn chunk(section_id: usize, chunk_id: usize, text: &str, score: f32) -> DocumentRecord {
+        DocumentRecord {
+            document_id: Some(0),
+            section_id: Some(section_id),
+            chunk_id: Some(chunk_id),
+            text: Some(text.to_string()),
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    fn words(from: usize, count: usize) -> String {
+        (from..from + count)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn pack(max_prompt_tokens: usize, records: Vec<DocumentRecord>) -> PackedContext {
+        pack_context(max_prompt_tokens, MODEL, &template(), "why?", &[], records)
+            .expect("the question fits")
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let packed = pack(
+            1000,
+            vec![
+                chunk(1, 0, "the first chunk", 0.9),
+                chunk(1, 0, "the first chunk", 0.8),
+                chunk(2, 0, "the first chunk", 0.7),
+            ],
+        );
+        assert_eq!(packed.records.len(), 1);
+        let reasons = packed
+            .report
+            .dropped
+            .iter()
+            .map(|b| b.reason)
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, vec![PackReason::Duplicate, PackReason::Duplicate]);
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_chunks() {
+        let packed = pack(
+            1000,
+            vec![
+                chunk(1, 3, &words(0, 20), 0.9),
+                // shares words 10..20 with chunk 3
+                chunk(1, 4, &words(10, 20), 0.8),
+                chunk(1, 2, "what came before", 0.7),
+                chunk(2, 5, "another section", 0.6),
+            ],
+        );
+        assert_eq!(packed.records.len(), 2);
+        let merged = &packed.records[0];
+        assert_eq!(merged.chunk_id, Some(2));
+        assert_eq!(
+            merged.text.as_deref(),
+            Some(format!("what came before {}", words(0, 30)).as_str())
+        );
+        assert_eq!(packed.report.merged.len(), 2);
+        assert!(packed.report.dropped.is_empty());
+    }
+
+    #[test]
+    fn trims_the_first_block_that_does_not_fit() {
+        let packed = pack(
+            400,
+            vec![
+                chunk(1, 0, &words(0, 100), 0.9),
+                chunk(2, 0, &words(1000, 300), 0.8),
+                chunk(3, 0, &words(2000, 300), 0.7),
+            ],
+        );
+        let report = &packed.report;
+        assert!(report.used_tokens <= report.budget);
+        assert_eq!(packed.records.len(), 2);
+        assert_eq!(packed.records[0].text, Some(words(0, 100)));
+        let trimmed = packed.records[1].text.as_deref().unwrap_or_default();
+        assert!(words(1000, 300).starts_with(trimmed) && trimmed.len() < words(1000, 300).len());
+        assert_eq!(report.trimmed.len(), 1);
+        assert_eq!(report.trimmed[0].section_id, Some(2));
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].reason, PackReason::OverBudget);
+    }
+
+    #[test]
+    fn refuses_a_question_over_the_limit() {
+        let result = pack_context(5, MODEL, &template(), &words(0, 50), &[], vec![]);
+        assert!(matches!(result, Err(ApiError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use api_types::{Diversity, DocumentRecord};
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let (dot, na, nb) = a
+        .iter()
+        .zip(b.iter())
+        .fold((0.0_f32, 0.0_f32, 0.0_f32), |(dot, na, nb), (x, y)| {
+            (dot + x * y, na + x * x, nb + y * y)
+        });
+    if na == 0.0 || nb == 0.0 {
+        0.0
+    } else {
+        dot / (na.sqrt() * nb.sqrt())
+    }
+}
+
+// Greedily pick `topn` records from `candidates` (which come in similarity
+// order). With `mmr_lambda` set, each step takes the candidate maximizing
+// `lambda * sim(query, c) - (1 - lambda) * max(sim(c, selected))`; otherwise
+// the original order is kept. `max_per_document` is applied in both cases.
+pub fn diversify(
+    query_vec: &[f32],
+    candidates: Vec<DocumentRecord>,
+    topn: usize,
+    diversity: &Diversity,
+) -> Vec<DocumentRecord> {
+    let mut selected = Vec::<DocumentRecord>::new();
+    let mut per_document = HashMap::<Option<usize>, usize>::new();
+
+    // `None` marks a candidate that has already been picked
+    let mut relevance = candidates
+        .iter()
+        .map(|r| match &r.vec {
+            Some(v) => Some(cosine(query_vec, v)),
+            None => Some(r.score.unwrap_or(0.0)),
+        })
+        .collect::<Vec<_>>();
+
+    while selected.len() < topn {
+        let mut best: Option<(usize, f32)> = None;
+        for (i, r) in candidates.iter().enumerate() {
+            let Some(rel) = relevance[i] else {
+                continue;
+            };
+            if let Some(cap) = diversity.max_per_document {
+                if per_document.get(&r.document_id).copied().unwrap_or(0) >= cap {
+                    continue;
+                }
+            }
+            let value = match (diversity.mmr_lambda, &r.vec) {
+                (Some(lambda), Some(v)) => {
+                    let redundancy = selected
+                        .iter()
+                        .filter_map(|s| s.vec.as_ref().map(|sv| cosine(v, sv)))
+                        .fold(0.0_f32, f32::max);
+                    lambda * rel - (1.0 - lambda) * redundancy
+                }
+                (Some(lambda), None) => lambda * rel,
+                // keep the similarity order when only the document cap is used
+                (None, _) => -(i as f32),
+            };
+            if best.map_or(true, |(_, b)| value > b) {
+                best = Some((i, value));
+            }
+        }
+        let Some((i, _)) = best else {
+            break;
+        };
+        relevance[i] = None;
+        let record = candidates[i].clone();
+        *per_document.entry(record.document_id).or_default() += 1;
+        selected.push(record);
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(document_id: usize, chunk_id: usize, vec: Vec<f32>) -> DocumentRecord {
+        DocumentRecord {
+            document_id: Some(document_id),
+            chunk_id: Some(chunk_id),
+            vec: Some(vec),
+            ..Default::default()
+        }
+    }
+
+    fn chunk_ids(records: &[DocumentRecord]) -> Vec<usize> {
+        records.iter().filter_map(|r| r.chunk_id).collect()
+    }
+
+    #[test]
+    fn mmr_skips_a_near_duplicate() {
+        let query = [1.0, 0.0];
+        let candidates = vec![
+            record(0, 0, vec![1.0, 0.0]),
+            record(0, 1, vec![0.99, 0.01]),
+            record(1, 2, vec![0.7, 0.7]),
+        ];
+        let diversity = Diversity {
+            mmr_lambda: Some(0.3),
+            ..Default::default()
+        };
+        let picked = diversify(&query, candidates.clone(), 2, &diversity);
+        assert_eq!(chunk_ids(&picked), vec![0, 2]);
+
+        // pure relevance keeps the similarity order
+        let diversity = Diversity {
+            mmr_lambda: Some(1.0),
+            ..Default::default()
+        };
+        let picked = diversify(&query, candidates, 2, &diversity);
+        assert_eq!(chunk_ids(&picked), vec![0, 1]);
+    }
+
+    #[test]
+    fn caps_the_hits_per_document() {
+        let candidates = vec![
+            record(0, 0, vec![1.0, 0.0]),
+            record(0, 1, vec![1.0, 0.0]),
+            record(0, 2, vec![1.0, 0.0]),
+            record(1, 3, vec![0.0, 1.0]),
+        ];
+        let diversity = Diversity {
+            max_per_document: Some(2),
+            ..Default::default()
+        };
+        let picked = diversify(&[1.0, 0.0], candidates, 4, &diversity);
+        assert_eq!(chunk_ids(&picked), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn cosine_of_a_zero_vector_is_zero() {
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert!((cosine(&[1.0, 1.0], &[2.0, 2.0]) - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::json;
+
+use crate::config::EmbeddingConfig;
+use crate::llm::check_openai_api;
+
+// `/embeddings` of the OpenAI API or of any server speaking it. The vectors
+// have to come from the model the collections were built with.
+pub struct OpenAiEmbeddings {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiEmbeddings {
+    pub fn new(config: &EmbeddingConfig, default_base_url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: config
+                .base_url
+                .clone()
+                .unwrap_or_else(|| default_base_url.to_string()),
+            api_key: std::env::var(&config.api_key_env).ok(),
+            model: config.model.clone(),
+        }
+    }
+
+    pub async fn check(&self) -> Result<()> {
+        check_openai_api(&self.client, &self.base_url, self.api_key.as_deref()).await
+    }
+
+    pub async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let mut http = self
+            .client
+            .post(format!(
+                "{}/embeddings",
+                self.base_url.trim_end_matches('/')
+            ))
+            .json(&json!({"model": self.model, "input": texts}));
+        if let Some(key) = &self.api_key {
+            http = http.bearer_auth(key);
+        }
+        let response = http.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!(
+                "{} answered {}: {}",
+                self.model,
+                status,
+                response.text().await?
+            );
+        }
+        let body: serde_json::Value = response.json().await?;
+        body["data"]
+            .as_array()
+            .ok_or_else(|| anyhow!("{} returned no embeddings", self.model))?
+            .iter()
+            .map(|d| {
+                serde_json::from_value::<Vec<f32>>(d["embedding"].clone())
+                    .map_err(|e| anyhow!("invalid embedding from {}: {}", self.model, e))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,150 @@
+use api_types::ErrorBody;
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::metrics;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// the request is malformed or out of range
+    BadRequest(String),
+    /// nothing relevant was found for the query
+    NotFound(String),
+    /// the API key is missing or unknown
+    Unauthorized(String),
+    /// the API key may not do this, it needs `admin`
+    Forbidden(String),
+    /// too many requests from the key, and the seconds until it may retry
+    RateLimited(String, u64),
+    /// the key used up its daily token or cost quota
+    QuotaExceeded(String),
+    /// the LLM or embedding provider returned an error
+    Upstream(String),
+    /// the vector store can't be reached
+    Unavailable(String),
+    /// a dependency didn't answer in time
+    Timeout(String),
+    /// the client stopped the request, only logged
+    Cancelled(String),
+    Internal(String),
+}
+
+fn is_timeout(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("timeout") || message.contains("timed out") || message.contains("deadline")
+}
+
+impl ApiError {
+    pub fn vector_store(err: impl std::fmt::Display) -> Self {
+        let message = format!("vector store: {}", err);
+        if is_timeout(&message) {
+            ApiError::Timeout(message)
+        } else {
+            ApiError::Unavailable(message)
+        }
+    }
+
+    pub fn upstream(err: impl std::fmt::Display) -> Self {
+        let message = format!("{}", err);
+        if is_timeout(&message) {
+            ApiError::Timeout(message)
+        } else {
+            ApiError::Upstream(message)
+        }
+    }
+
+    pub fn internal(err: impl std::fmt::Display) -> Self {
+        ApiError::Internal(format!("{}", err))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::RateLimited(..) | ApiError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            // nginx's "client closed request"
+            ApiError::Cancelled(_) => StatusCode::from_u16(499).expect("valid status code"),
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::RateLimited(..) => "rate_limited",
+            ApiError::QuotaExceeded(_) => "quota_exceeded",
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Cancelled(_) => "cancelled",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Upstream(_)
+                | ApiError::Unavailable(_)
+                | ApiError::Timeout(_)
+                | ApiError::RateLimited(..)
+        )
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(m)
+            | ApiError::NotFound(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::Forbidden(m)
+            | ApiError::RateLimited(m, _)
+            | ApiError::QuotaExceeded(m)
+            | ApiError::Upstream(m)
+            | ApiError::Unavailable(m)
+            | ApiError::Timeout(m)
+            | ApiError::Cancelled(m)
+            | ApiError::Internal(m) => m,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code().to_string(),
+            message: self.message().to_string(),
+            retryable: self.retryable(),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        tracing::warn!("{}", self);
+        metrics::count_error(self.code());
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let ApiError::RateLimited(_, retry_after) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
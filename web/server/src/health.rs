@@ -0,0 +1,61 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use api_types::filters::Level;
+use api_types::{DependencyCheck, Readiness};
+use futures::future::join_all;
+
+use crate::state::AppState;
+
+// short enough for a load balancer probe, a dependency slower than this is
+// as good as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn timed(name: String, check: impl Future<Output = Result<()>>) -> DependencyCheck {
+    let start = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer within {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    DependencyCheck {
+        name,
+        ok: error.is_none(),
+        error,
+        latency_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+// the three collections the searches go to
+async fn check_vector_store(state: &AppState) -> Result<()> {
+    let collections = state.client.list_collections().await?;
+    let missing: Vec<String> = [Level::Documents, Level::Sections, Level::Chunks]
+        .into_iter()
+        .map(|level| state.collection(level))
+        .filter(|name| !collections.collections.iter().any(|c| &c.name == name))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("missing collections {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+// Whether the server can answer: the vector store, the embeddings and the
+// default model with its fallbacks, checked concurrently.
+pub async fn readiness(state: &AppState) -> Readiness {
+    let models = std::iter::once(&state.config.model).chain(&state.config.fallback_models);
+    let llm_checks =
+        join_all(models.map(|model| timed(format!("llm:{}", model), state.llms.check(model))));
+    let (vector_store, embeddings, llms) = tokio::join!(
+        timed("vector_store".to_string(), check_vector_store(state)),
+        timed("embeddings".to_string(), state.embeddings.check()),
+        llm_checks,
+    );
+    let mut checks = vec![vector_store, embeddings];
+    checks.extend(llms);
+    Readiness {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use api_types::chunk_meta::{chunk_types, section_title};
+use api_types::filters::{and, match_keyword, Level};
+use api_types::nxml::nxml_sections;
+use api_types::DocumentFormat;
+use qdrant_client::client::Payload;
+use qdrant_client::prelude::*;
+use qdrant_client::qdrant::{points_selector::PointsSelectorOneOf, Filter, PointsSelector};
+
+use crate::error::ApiError;
+use crate::query_qdrant_db::{payload_usize, scroll_all};
+use crate::state::AppState;
+use crate::tokens::count_tokens;
+
+// same chunking as `get_openai_embed_vec`, so uploaded documents are searched
+// the way the indexed collection is
+const MAX_TOKENS_PER_CHUNK: usize = 256;
+const CHUNK_OVERLAP: usize = 32;
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+// The text of each section, with the `title: ==`, `content: START`, `table:`,
+// `list:` and `reference: ` markers of `api_types::nxml`.
+pub fn parse_sections(format: DocumentFormat, bytes: &[u8]) -> Result<Vec<String>> {
+    let sections = match format {
+        DocumentFormat::Nxml => nxml_sections(&String::from_utf8_lossy(bytes))?,
+        DocumentFormat::Pdf => pdf_sections(bytes)?,
+        DocumentFormat::Markdown => markdown_sections(&String::from_utf8_lossy(bytes)),
+    };
+    Ok(sections.into_iter().filter(|s| !s.is_empty()).collect())
+}
+
+// a PDF has no section markup, each page becomes a section
+fn pdf_sections(bytes: &[u8]) -> Result<Vec<String>> {
+    let doc = lopdf::Document::load_mem(bytes)?;
+    if doc.is_encrypted() {
+        bail!("encrypted PDFs are not supported");
+    }
+    doc.get_pages()
+        .keys()
+        .map(|page| {
+            let text = doc
+                .extract_text(&[*page])
+                .map_err(|e| anyhow!("can't extract the text of page {}: {}", page, e))?;
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            Ok(if text.is_empty() {
+                String::new()
+            } else {
+                format!("content: START {} END", text)
+            })
+        })
+        .collect()
+}
+
+// a section per heading, blank lines separate the blocks of a section
+fn markdown_sections(text: &str) -> Vec<String> {
+    let mut sections = Vec::new();
+    let mut blocks: Vec<String> = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+
+    fn flush_block(block: &mut Vec<&str>, blocks: &mut Vec<String>) {
+        if block.is_empty() {
+            return;
+        }
+        let is_list = block.iter().all(|l| {
+            let l = l.trim_start();
+            l.starts_with("- ")
+                || l.starts_with("* ")
+                || l.split_once(". ")
+                    .is_some_and(|(n, _)| n.chars().all(|c| c.is_ascii_digit()))
+        });
+        let text = block.join("\n");
+        blocks.push(if block[0].trim_start().starts_with('|') {
+            format!("table:\n{}", text)
+        } else if is_list {
+            format!("list:\n{}", text)
+        } else {
+            format!("content: START {} END", block.join(" "))
+        });
+        block.clear();
+    }
+
+    for line in text.lines() {
+        if let Some(heading) = line.strip_prefix('#') {
+            flush_block(&mut block, &mut blocks);
+            if !blocks.is_empty() {
+                sections.push(blocks.join("\n"));
+                blocks.clear();
+            }
+            blocks.push(format!(
+                "title: == {} ==",
+                heading.trim_start_matches('#').trim()
+            ));
+        } else if line.trim().is_empty() {
+            flush_block(&mut block, &mut blocks);
+        } else {
+            block.push(line);
+        }
+    }
+    flush_block(&mut block, &mut blocks);
+    if !blocks.is_empty() {
+        sections.push(blocks.join("\n"));
+    }
+    sections
+}
+
+pub struct Chunk {
+    pub section_id: usize,
+    pub chunk_id: usize,
+    pub text: String,
+}
+
+// overlapping windows of `MAX_TOKENS_PER_CHUNK` tokens over each section
+pub fn chunk_sections(sections: &[String]) -> Result<Vec<Chunk>> {
+    let bpe = tiktoken_rs::p50k_base()?;
+    let mut chunks = Vec::new();
+    for (section_id, section) in sections.iter().enumerate() {
+        let tokens = bpe.split_by_token(section, false)?;
+        // a window starting inside the overlap of the previous one would hold
+        // nothing new
+        for (chunk_id, start) in (0..tokens.len())
+            .step_by(MAX_TOKENS_PER_CHUNK - CHUNK_OVERLAP)
+            .take_while(|start| *start == 0 || start + CHUNK_OVERLAP < tokens.len())
+            .enumerate()
+        {
+            let end = (start + MAX_TOKENS_PER_CHUNK).min(tokens.len());
+            chunks.push(Chunk {
+                section_id,
+                chunk_id,
+                text: tokens[start..end].join(""),
+            });
+        }
+    }
+    Ok(chunks)
+}
+
+// `cancelled` is checked between the embedding requests; each goes through
+// the embedding stage, shared with the queries, and is counted against
+// `api_key`
+pub async fn embed_chunks(
+    state: &AppState,
+    chunks: &[Chunk],
+    api_key: Option<&str>,
+    cancelled: impl Fn() -> bool,
+) -> Result<Vec<Vec<f32>>> {
+    let model = &state.config.embedding.model;
+    let mut vectors = Vec::with_capacity(chunks.len());
+    for batch in chunks.chunks(EMBEDDING_BATCH_SIZE) {
+        if cancelled() {
+            bail!("cancelled");
+        }
+        let texts = batch.iter().map(|c| c.text.clone()).collect();
+        let embedded = state
+            .limits
+            .embed
+            .run(async {
+                state
+                    .embeddings
+                    .embed_texts(texts)
+                    .await
+                    .map_err(|e| ApiError::upstream(format!("embedding: {}", e)))
+            })
+            .await?;
+        vectors.extend(embedded);
+        let tokens = batch.iter().map(|c| count_tokens(model, &c.text)).sum();
+        state
+            .audit
+            .spend(api_key, "ingest_embedding", model, tokens, 0)
+            .await;
+    }
+    if vectors.len() != chunks.len() {
+        bail!(
+            "got {} embeddings for {} chunks",
+            vectors.len(),
+            chunks.len()
+        );
+    }
+    Ok(vectors)
+}
+
+fn mean(vectors: &[&Vec<f32>]) -> Vec<f32> {
+    let mut mean = vec![0_f32; vectors.first().map(|v| v.len()).unwrap_or_default()];
+    for v in vectors {
+        mean.iter_mut().zip(v.iter()).for_each(|(m, x)| *m += x);
+    }
+    mean.iter_mut().for_each(|m| *m /= vectors.len() as f32);
+    mean
+}
+
+// Add the document to the three live collections, with the payloads and the
+// mean pooled section and document vectors of `create_qdrant_db`.
+pub async fn upsert_document(
+    state: &AppState,
+    file_name: &str,
+    document_id: usize,
+    chunks: &[Chunk],
+    vectors: Vec<Vec<f32>>,
+) -> Result<()> {
+    // the title line opens the first chunk of its section
+    let titles = chunks
+        .iter()
+        .filter(|c| c.chunk_id == 0)
+        .filter_map(|c| Some((c.section_id, section_title(&c.text)?)))
+        .collect::<BTreeMap<_, _>>();
+    let base_payload = |section_id: Option<usize>| {
+        let mut payload = Payload::new();
+        payload.insert("file_name", file_name.to_string());
+        payload.insert("document_id", document_id.to_string());
+        if let Some(section_id) = section_id {
+            payload.insert("section_id", section_id.to_string());
+            if let Some(title) = titles.get(&section_id) {
+                payload.insert("section_title", title.clone());
+            }
+        }
+        payload
+    };
+
+    let chunk_points = chunks
+        .iter()
+        .zip(&vectors)
+        .map(|(c, v)| {
+            let mut payload = base_payload(Some(c.section_id));
+            payload.insert("chunk_id", c.chunk_id.to_string());
+            payload.insert("chunk_types", chunk_types(&c.text));
+            payload.insert("text", c.text.clone());
+            let id = document_id << 32 | c.section_id << 16 | c.chunk_id;
+            PointStruct::new(id as u64, v.clone(), payload)
+        })
+        .collect::<Vec<_>>();
+
+    let mut by_section = BTreeMap::<usize, Vec<(&Chunk, &Vec<f32>)>>::new();
+    for (c, v) in chunks.iter().zip(&vectors) {
+        by_section.entry(c.section_id).or_default().push((c, v));
+    }
+    let section_points = by_section
+        .iter()
+        .map(|(section_id, members)| {
+            let mut payload = base_payload(Some(*section_id));
+            let mut types = members
+                .iter()
+                .flat_map(|(c, _)| chunk_types(&c.text))
+                .collect::<Vec<_>>();
+            types.sort();
+            types.dedup();
+            payload.insert("chunk_types", types);
+            let vecs = members.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+            let id = document_id << 32 | section_id << 16;
+            PointStruct::new(id as u64, mean(&vecs), payload)
+        })
+        .collect::<Vec<_>>();
+
+    let document_point = PointStruct::new(
+        (document_id << 32) as u64,
+        mean(&vectors.iter().collect::<Vec<_>>()),
+        base_payload(None),
+    );
+
+    for (level, points) in [
+        (Level::Chunks, chunk_points),
+        (Level::Sections, section_points),
+        (Level::Documents, vec![document_point]),
+    ] {
+        state
+            .client
+            .upsert_points_blocking(state.collection(level), points, None)
+            .await?;
+    }
+    Ok(())
+}
+
+// Remove every point of `document_id`, the document first so that it stops
+// being found while its sections and chunks go.
+pub async fn delete_document(state: &AppState, document_id: usize) -> Result<()> {
+    let selector = PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Filter(Filter {
+            must: vec![match_keyword("document_id", document_id.to_string())],
+            ..Default::default()
+        })),
+    };
+    for level in [Level::Documents, Level::Sections, Level::Chunks] {
+        state
+            .client
+            .delete_points_blocking(state.collection(level), &selector, None)
+            .await?;
+    }
+    Ok(())
+}
+
+// the id of the document indexed from `file_name`, if there is one
+pub async fn find_document(state: &AppState, file_name: &str) -> Result<Option<usize>, ApiError> {
+    let filter = and(
+        None,
+        vec![match_keyword("file_name", file_name.to_string())],
+    );
+    Ok(scroll_all(state, Level::Documents, filter, false)
+        .await?
+        .first()
+        .and_then(|p| payload_usize(&p.payload, "document_id")))
+}
+
+pub async fn next_document_id(state: &AppState) -> Result<usize, ApiError> {
+    Ok(scroll_all(state, Level::Documents, None, false)
+        .await?
+        .iter()
+        .filter_map(|p| payload_usize(&p.payload, "document_id"))
+        .max()
+        .map_or(0, |id| id + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered_words(count: usize) -> String {
+        (0..count)
+            .map(|i| format!("w{}", i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn splits_markdown_at_headings() {
+        let text = "intro text\n\n# Title\nline one\nline two\n\n- a\n- b\n\n\
+                    | x | y |\n|---|---|\n\n## Sub\n1. first\n2. second\n";
+        let sections = markdown_sections(text);
+        assert_eq!(
+            sections,
+            vec![
+                "content: START intro text END".to_string(),
+                "title: == Title ==\ncontent: START line one line two END\n\
+                 list:\n- a\n- b\ntable:\n| x | y |\n|---|---|"
+                    .to_string(),
+                "title: == Sub ==\nlist:\n1. first\n2. second".to_string(),
+            ]
+        );
+        assert_eq!(section_title(&sections[1]), Some("Title".to_string()));
+        assert_eq!(section_title(&sections[0]), None);
+        let types = chunk_types(&sections[1]);
+        assert_eq!(types, vec!["paragraph", "table", "list"]);
+    }
+
+    #[test]
+    fn chunks_overlap_by_a_fixed_stride() {
+        let section = numbered_words(600);
+        let tokens = tiktoken_rs::p50k_base()
+            .unwrap()
+            .split_by_token(&section, false)
+            .unwrap();
+        let stride = MAX_TOKENS_PER_CHUNK - CHUNK_OVERLAP;
+        let chunks = chunk_sections(&["short".to_string(), section]).unwrap();
+
+        assert_eq!((chunks[0].section_id, chunks[0].chunk_id), (0, 0));
+        assert_eq!(chunks[0].text, "short");
+        let chunks = &chunks[1..];
+        for (i, chunk) in chunks.iter().enumerate() {
+            let start = i * stride;
+            let end = (start + MAX_TOKENS_PER_CHUNK).min(tokens.len());
+            assert_eq!((chunk.section_id, chunk.chunk_id), (1, i));
+            assert_eq!(chunk.text, tokens[start..end].join(""));
+        }
+        // the last window reaches the end and isn't inside the one before it
+        let last = (chunks.len() - 1) * stride;
+        assert!(last + MAX_TOKENS_PER_CHUNK >= tokens.len());
+        assert!(last + CHUNK_OVERLAP < tokens.len());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use api_types::{DocumentFormat, Job, JobStatus};
+use tokio::sync::Notify;
+
+use crate::error::ApiError;
+use crate::ingest;
+use crate::sessions::now_secs;
+use crate::state::SharedState;
+
+struct JobEntry {
+    job: Job,
+    /// the uploaded file, until the worker takes it
+    upload: Option<Vec<u8>>,
+    cancel: Arc<AtomicBool>,
+    /// the key that uploaded the file, the embeddings are counted against it
+    api_key: Option<String>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    entries: BTreeMap<u64, JobEntry>,
+    pending: VecDeque<u64>,
+    next_id: u64,
+}
+
+// What the worker needs to run a job.
+struct Task {
+    job_id: u64,
+    file_name: String,
+    format: DocumentFormat,
+    cancel: Arc<AtomicBool>,
+    api_key: Option<String>,
+}
+
+// Upload ingestion jobs, run one at a time by `run_worker` so that two
+// uploads can't pick the same document id. Jobs are kept in memory, the
+// list starts empty after a restart.
+pub struct JobQueue {
+    jobs: Mutex<Jobs>,
+    ready: Notify,
+    /// queued jobs hold their upload, past this many new ones are refused
+    max_pending: usize,
+}
+
+impl JobQueue {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            jobs: Mutex::default(),
+            ready: Notify::new(),
+            max_pending,
+        }
+    }
+
+    pub fn submit(
+        &self,
+        file_name: &str,
+        format: DocumentFormat,
+        upload: Vec<u8>,
+        api_key: Option<&str>,
+    ) -> Result<Job, ApiError> {
+        let mut jobs = self.jobs.lock().expect("jobs lock");
+        if jobs.pending.len() >= self.max_pending {
+            return Err(ApiError::Unavailable(format!(
+                "{} uploads are already waiting to be indexed, try again later",
+                jobs.pending.len()
+            )));
+        }
+        let job = Job {
+            job_id: jobs.next_id,
+            file_name: file_name.to_string(),
+            format,
+            status: JobStatus::Queued,
+            stage: None,
+            document_id: None,
+            sections: 0,
+            chunks: 0,
+            error: None,
+            created_at: now_secs(),
+            finished_at: None,
+        };
+        jobs.next_id += 1;
+        jobs.pending.push_back(job.job_id);
+        jobs.entries.insert(
+            job.job_id,
+            JobEntry {
+                job: job.clone(),
+                upload: Some(upload),
+                cancel: Arc::new(AtomicBool::new(false)),
+                api_key: api_key.map(|k| k.to_string()),
+            },
+        );
+        self.ready.notify_one();
+        Ok(job)
+    }
+
+    pub fn get(&self, job_id: u64) -> Result<Job, ApiError> {
+        let jobs = self.jobs.lock().expect("jobs lock");
+        jobs.entries
+            .get(&job_id)
+            .map(|e| e.job.clone())
+            .ok_or_else(|| ApiError::NotFound(format!("job {} not found", job_id)))
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        let jobs = self.jobs.lock().expect("jobs lock");
+        jobs.entries.values().map(|e| e.job.clone()).collect()
+    }
+
+    // A queued job is cancelled right away, a running one at its next check;
+    // once the document is being indexed the job runs to the end.
+    pub fn cancel(&self, job_id: u64) -> Result<Job, ApiError> {
+        let mut jobs = self.jobs.lock().expect("jobs lock");
+        let Some(entry) = jobs.entries.get_mut(&job_id) else {
+            return Err(ApiError::NotFound(format!("job {} not found", job_id)));
+        };
+        match entry.job.status {
+            JobStatus::Queued => {
+                entry.job.status = JobStatus::Cancelled;
+                entry.job.finished_at = Some(now_secs());
+                entry.upload = None;
+                let job = entry.job.clone();
+                jobs.pending.retain(|id| *id != job_id);
+                Ok(job)
+            }
+            JobStatus::Running => {
+                entry.cancel.store(true, Ordering::Relaxed);
+                Ok(entry.job.clone())
+            }
+            _ => Err(ApiError::BadRequest(format!(
+                "job {} has already finished",
+                job_id
+            ))),
+        }
+    }
+
+    // the next queued job with its uploaded file
+    fn next(&self) -> Option<(Task, Vec<u8>)> {
+        let mut jobs = self.jobs.lock().expect("jobs lock");
+        let job_id = jobs.pending.pop_front()?;
+        let entry = jobs.entries.get_mut(&job_id)?;
+        entry.job.status = JobStatus::Running;
+        let task = Task {
+            job_id,
+            file_name: entry.job.file_name.clone(),
+            format: entry.job.format,
+            cancel: entry.cancel.clone(),
+            api_key: entry.api_key.clone(),
+        };
+        Some((task, entry.upload.take().unwrap_or_default()))
+    }
+
+    fn update(&self, job_id: u64, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().expect("jobs lock");
+        if let Some(entry) = jobs.entries.get_mut(&job_id) {
+            f(&mut entry.job);
+        }
+    }
+
+    fn finish(&self, task: &Task, result: Result<()>) {
+        let cancelled = task.cancel.load(Ordering::Relaxed);
+        self.update(task.job_id, |job| {
+            job.stage = None;
+            job.finished_at = Some(now_secs());
+            job.status = match result {
+                Ok(()) => JobStatus::Succeeded,
+                Err(_) if cancelled => JobStatus::Cancelled,
+                Err(e) => {
+                    job.error = Some(format!("{:#}", e));
+                    JobStatus::Failed
+                }
+            };
+        });
+    }
+}
+
+// browsers may send a path, only the name is kept
+pub fn upload_file_name(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+}
+
+async fn run_job(state: &SharedState, task: &Task, upload: Vec<u8>) -> Result<()> {
+    let jobs = &state.jobs;
+    let stage = |name: &str| jobs.update(task.job_id, |job| job.stage = Some(name.to_string()));
+    let cancelled = || task.cancel.load(Ordering::Relaxed);
+
+    stage("parsing");
+    let format = task.format;
+    let sections =
+        tokio::task::spawn_blocking(move || ingest::parse_sections(format, &upload)).await??;
+    let chunks = ingest::chunk_sections(&sections)?;
+    if chunks.is_empty() {
+        bail!("no text found in {}", task.file_name);
+    }
+    jobs.update(task.job_id, |job| {
+        job.sections = sections.len();
+        job.chunks = chunks.len();
+    });
+
+    stage("embedding");
+    let api_key = task.api_key.as_deref();
+    let vectors = ingest::embed_chunks(state, &chunks, api_key, cancelled).await?;
+    if cancelled() {
+        bail!("cancelled");
+    }
+
+    stage("indexing");
+    if ingest::find_document(state, &task.file_name)
+        .await?
+        .is_some()
+    {
+        bail!("{} is already indexed", task.file_name);
+    }
+    let document_id = ingest::next_document_id(state).await?;
+    jobs.update(task.job_id, |job| job.document_id = Some(document_id));
+    let result =
+        ingest::upsert_document(state, &task.file_name, document_id, &chunks, vectors).await;
+    if result.is_err() {
+        // the levels are written one after the other, don't leave half of them
+        if let Err(e) = ingest::delete_document(state, document_id).await {
+            tracing::warn!(
+                "can't remove the points of document {}: {:#}",
+                document_id,
+                e
+            );
+        }
+    }
+    result
+}
+
+// runs the queued jobs one after the other, for the lifetime of the server
+pub async fn run_worker(state: SharedState) {
+    loop {
+        let Some((task, upload)) = state.jobs.next() else {
+            state.jobs.ready.notified().await;
+            continue;
+        };
+        tracing::info!("ingesting {} (job {})", task.file_name, task.job_id);
+        let result = run_job(&state, &task, upload).await;
+        if let Err(e) = &result {
+            tracing::warn!("job {} failed: {:#}", task.job_id, e);
+        }
+        state.jobs.finish(&task, result);
+        // set once indexing starts, a failed job may have written some points
+        let indexed = state
+            .jobs
+            .get(task.job_id)
+            .is_ok_and(|job| job.document_id.is_some());
+        if indexed {
+            let reason = format!("{} was indexed", task.file_name);
+            state.cache.clear(&reason);
+        }
+    }
+}
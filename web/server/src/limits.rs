@@ -0,0 +1,102 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+use crate::config::LimitsConfig;
+use crate::error::ApiError;
+
+// An expensive step of a request: a bounded number of calls run at once and
+// each has a deadline, the wait for a slot included, past which it fails
+// with 504.
+pub struct Stage {
+    name: &'static str,
+    timeout: Duration,
+    slots: Semaphore,
+}
+
+impl Stage {
+    fn new(name: &'static str, timeout_secs: u64, slots: usize) -> Self {
+        Self {
+            name,
+            timeout: Duration::from_secs(timeout_secs),
+            slots: Semaphore::new(slots),
+        }
+    }
+
+    pub async fn run<T>(
+        &self,
+        work: impl Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        let limited = async {
+            let _slot = self.slots.acquire().await.map_err(ApiError::internal)?;
+            work.await
+        };
+        tokio::time::timeout(self.timeout, limited)
+            .await
+            .map_err(|_| {
+                ApiError::Timeout(format!(
+                    "{} took longer than {}s",
+                    self.name,
+                    self.timeout.as_secs()
+                ))
+            })?
+    }
+}
+
+// Blocking work outlives the future that spawned it, the timeout of a stage
+// would free its slot while the thread keeps the CPU. The guard is held by
+// the future and the flag checked by the thread, which stops once the future
+// is dropped.
+#[derive(Default)]
+pub struct CancelOnDrop(Arc<AtomicBool>);
+
+impl CancelOnDrop {
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.0.clone()
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// The stages of answering a query, shared by every request so a burst waits
+// for slots instead of piling up on the upstream APIs and the reranker.
+pub struct Limits {
+    pub embed: Stage,
+    pub search: Stage,
+    pub rerank: Stage,
+    pub generate: Stage,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            embed: Stage::new(
+                "embedding",
+                config.embed_timeout_secs,
+                config.max_concurrent_embeddings,
+            ),
+            search: Stage::new(
+                "search",
+                config.search_timeout_secs,
+                config.max_concurrent_searches,
+            ),
+            rerank: Stage::new(
+                "reranking",
+                config.rerank_timeout_secs,
+                config.max_concurrent_reranks,
+            ),
+            generate: Stage::new(
+                "generation",
+                config.generate_timeout_secs,
+                config.max_concurrent_generations,
+            ),
+        }
+    }
+}
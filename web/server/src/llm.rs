@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use api_types::{GenerationParams, Turn};
+use async_trait::async_trait;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+use crate::config::{Backend, ModelConfig, ServerConfig};
+use crate::error::ApiError;
+use crate::local_llm;
+use crate::metrics;
+
+// the request settings over the model config ones
+fn with_model_defaults(params: &GenerationParams, model: &ModelConfig) -> GenerationParams {
+    GenerationParams {
+        model: Some(model.name.clone()),
+        temperature: params.temperature.or(model.temperature),
+        max_tokens: params.max_tokens.or(model.max_tokens),
+        seed: params.seed,
+    }
+}
+
+pub struct ChatRequest<'a> {
+    pub system: &'a str,
+    pub history: &'a [Turn],
+    pub prompt: &'a str,
+    pub params: &'a GenerationParams,
+}
+
+// The receiver of the tokens was dropped, the text so far must not pass for
+// a finished answer.
+pub fn client_gone() -> anyhow::Error {
+    ApiError::Cancelled("the client went away".to_string()).into()
+}
+
+fn is_cancelled(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(ApiError::Cancelled(_)))
+}
+
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    // Complete the request; with `tokens` set every piece of text is also sent
+    // as soon as it is generated, and a closed receiver fails with
+    // `client_gone`.
+    async fn complete(
+        &self,
+        request: &ChatRequest<'_>,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<String>;
+
+    // whether the backend can be reached, for `/readyz`
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// `GET /models`, cheap and free on every OpenAI compatible server
+pub async fn check_openai_api(
+    client: &reqwest::Client,
+    base_url: &str,
+    api_key: Option<&str>,
+) -> Result<()> {
+    let mut http = client.get(format!("{}/models", base_url.trim_end_matches('/')));
+    if let Some(key) = api_key {
+        http = http.bearer_auth(key);
+    }
+    let response = http.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("{} answered {}", base_url, status);
+    }
+    Ok(())
+}
+
+// `/chat/completions` of the OpenAI API or of any server speaking it.
+pub struct OpenAiBackend {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(model: &ModelConfig, default_base_url: &str) -> Self {
+        let base_url = model.base_url.as_deref().unwrap_or(default_base_url);
+        let api_key_env = model.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY");
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: std::env::var(api_key_env).ok(),
+            model: model.model_id().to_string(),
+        }
+    }
+
+    fn messages(request: &ChatRequest<'_>) -> Vec<serde_json::Value> {
+        let mut messages = vec![json!({"role": "system", "content": request.system})];
+        for turn in request.history {
+            messages.push(json!({"role": "user", "content": turn.question}));
+            messages.push(json!({"role": "assistant", "content": turn.answer}));
+        }
+        messages.push(json!({"role": "user", "content": request.prompt}));
+        messages
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn check(&self) -> Result<()> {
+        check_openai_api(&self.client, &self.base_url, self.api_key.as_deref()).await
+    }
+
+    async fn complete(
+        &self,
+        request: &ChatRequest<'_>,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<String> {
+        let mut body = json!({
+            "model": self.model,
+            "messages": Self::messages(request),
+            "stream": tokens.is_some(),
+        });
+        if let Some(t) = request.params.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(n) = request.params.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
+        if let Some(seed) = request.params.seed {
+            body["seed"] = json!(seed);
+        }
+
+        let mut http = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&body);
+        if let Some(key) = &self.api_key {
+            http = http.bearer_auth(key);
+        }
+        let response = http.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{} answered {}: {}", self.model, status, response.text().await?);
+        }
+
+        let Some(tokens) = tokens else {
+            let completion: serde_json::Value = response.json().await?;
+            return completion["choices"][0]["message"]["content"]
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| anyhow!("{} returned no text", self.model));
+        };
+
+        // server-sent events, one `data: {json}` line per delta
+        let mut text = String::new();
+        let mut buffer = String::new();
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            buffer.push_str(&String::from_utf8_lossy(&chunk?));
+            while let Some(end) = buffer.find('\n') {
+                let line = buffer[..end].trim().to_string();
+                buffer.drain(..=end);
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    return Ok(text);
+                }
+                let delta: serde_json::Value = serde_json::from_str(data)?;
+                if let Some(piece) = delta["choices"][0]["delta"]["content"].as_str() {
+                    text.push_str(piece);
+                    if tokens.send(piece.to_string()).await.is_err() {
+                        return Err(client_gone());
+                    }
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+// quantized Mistral through candle, see `local_llm`
+pub struct LocalBackend {
+    config: crate::config::LocalLlmConfig,
+}
+
+#[async_trait]
+impl LlmBackend for LocalBackend {
+    async fn complete(
+        &self,
+        request: &ChatRequest<'_>,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<String> {
+        let mut config = self.config.clone();
+        if let Some(t) = request.params.temperature {
+            config.temperature = t as f64;
+        }
+        if let Some(n) = request.params.max_tokens {
+            config.max_tokens = n;
+        }
+        if let Some(seed) = request.params.seed {
+            config.seed = seed;
+        }
+        local_llm::generate(
+            &config,
+            request.system,
+            request.history,
+            request.prompt,
+            tokens,
+        )
+        .await
+    }
+}
+
+// Deterministic answers for tests and demos, no model involved. The text
+// cites the first source so the citation path is exercised as well.
+pub struct MockBackend;
+
+#[async_trait]
+impl LlmBackend for MockBackend {
+    async fn complete(
+        &self,
+        request: &ChatRequest<'_>,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<String> {
+        let text = format!(
+            "This is a mock answer [1] to a prompt of {} characters after {} turns.",
+            request.prompt.chars().count(),
+            request.history.len()
+        );
+        if let Some(tokens) = tokens {
+            for word in text.split_inclusive(' ') {
+                if tokens.send(word.to_string()).await.is_err() {
+                    return Err(client_gone());
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+pub struct Completion {
+    pub text: String,
+    /// the model that answered, a fallback if the requested one failed
+    pub model: String,
+}
+
+// The configured models by name, with the fallbacks tried in order when the
+// requested model errors or times out.
+pub struct LlmRegistry {
+    models: HashMap<String, (ModelConfig, Arc<dyn LlmBackend>)>,
+    fallback_models: Vec<String>,
+    timeout: Duration,
+    /// `local.offline`, a local model doesn't fall back to a remote one
+    offline: bool,
+}
+
+impl LlmRegistry {
+    pub fn new(config: &ServerConfig) -> Self {
+        let models = config
+            .models
+            .iter()
+            .map(|model| {
+                let backend: Arc<dyn LlmBackend> = match model.backend {
+                    Backend::Openai => {
+                        Arc::new(OpenAiBackend::new(model, &config.openai_base_url))
+                    }
+                    Backend::Local => Arc::new(LocalBackend {
+                        config: config.local.clone(),
+                    }),
+                    Backend::Mock => Arc::new(MockBackend),
+                };
+                (model.name.clone(), (model.clone(), backend))
+            })
+            .collect();
+        Self {
+            models,
+            fallback_models: config.fallback_models.clone(),
+            timeout: Duration::from_secs(config.llm_timeout_secs),
+            offline: config.local.offline,
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.models.contains_key(name)
+    }
+
+    pub async fn check(&self, name: &str) -> Result<()> {
+        let Some((_, backend)) = self.models.get(name) else {
+            bail!("unknown model {}", name);
+        };
+        backend.check().await
+    }
+
+    pub async fn complete(
+        &self,
+        model: &str,
+        system: &str,
+        history: &[Turn],
+        prompt: &str,
+        params: &GenerationParams,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<Completion, ApiError> {
+        let _timer = metrics::stage("generate");
+        let is_local = |name: &str| {
+            self.models
+                .get(name)
+                .is_some_and(|(config, _)| config.backend == Backend::Local)
+        };
+        let local_only = self.offline && is_local(model);
+        let mut chain = vec![model.to_string()];
+        chain.extend(
+            self.fallback_models
+                .iter()
+                .filter(|name| name.as_str() != model)
+                .filter(|name| !local_only || is_local(name))
+                .cloned(),
+        );
+
+        let mut last_error = ApiError::BadRequest(format!("unknown model {}", model));
+        for name in chain {
+            let Some((model_config, backend)) = self.models.get(&name) else {
+                continue;
+            };
+            let params = with_model_defaults(params, model_config);
+            let request = ChatRequest {
+                system,
+                history,
+                prompt,
+                params: &params,
+            };
+
+            // once part of an answer reached the client, trying another model
+            // would mix two answers
+            let sent = Arc::new(AtomicBool::new(false));
+            let (attempt_tokens, mut attempt_rx) = mpsc::channel::<String>(64);
+            let forward = tokens.cloned().map(|tokens| {
+                let sent = sent.clone();
+                // whether the client went away before the last piece
+                tokio::spawn(async move {
+                    while let Some(piece) = attempt_rx.recv().await {
+                        sent.store(true, Ordering::Relaxed);
+                        if tokens.send(piece).await.is_err() {
+                            return true;
+                        }
+                    }
+                    false
+                })
+            });
+            let attempt = tokio::time::timeout(
+                self.timeout,
+                backend.complete(&request, tokens.map(|_| &attempt_tokens)),
+            )
+            .await;
+            drop(attempt_tokens);
+            let gone = match forward {
+                Some(forward) => forward.await.unwrap_or(false),
+                None => false,
+            };
+            // no fallback and no partial answer, nobody is waiting for it
+            if gone || matches!(&attempt, Ok(Err(e)) if is_cancelled(e)) {
+                return Err(ApiError::Cancelled(format!(
+                    "{}: the client went away",
+                    name
+                )));
+            }
+
+            last_error = match attempt {
+                Ok(Ok(text)) => return Ok(Completion { text, model: name }),
+                Ok(Err(e)) => match model_config.backend {
+                    Backend::Local => ApiError::Internal(format!("{}: {}", name, e)),
+                    _ => ApiError::upstream(format!("{}: {}", name, e)),
+                },
+                Err(_) => ApiError::Timeout(format!(
+                    "{} didn't answer within {}s",
+                    name,
+                    self.timeout.as_secs()
+                )),
+            };
+            tracing::warn!("{}", last_error);
+            if sent.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> LlmRegistry {
+        LlmRegistry::new(&ServerConfig {
+            model: "mock".to_string(),
+            fallback_models: vec!["gpt-3.5-turbo".to_string()],
+            ..Default::default()
+        })
+    }
+
+    async fn complete(
+        registry: &LlmRegistry,
+        tokens: Option<&mpsc::Sender<String>>,
+    ) -> Result<Completion, ApiError> {
+        let params = GenerationParams::default();
+        registry
+            .complete("mock", "system", &[], "What is CFTR?", &params, tokens)
+            .await
+    }
+
+    #[tokio::test]
+    async fn streams_the_whole_answer() {
+        let registry = registry();
+        let (tokens, mut rx) = mpsc::channel(64);
+        let completion = complete(&registry, Some(&tokens)).await.unwrap();
+        drop(tokens);
+        let mut streamed = String::new();
+        while let Some(piece) = rx.recv().await {
+            streamed.push_str(&piece);
+        }
+        assert_eq!(completion.model, "mock");
+        assert_eq!(streamed, completion.text);
+    }
+
+    #[tokio::test]
+    async fn a_closed_receiver_cancels_without_fallback() {
+        let registry = registry();
+        let (tokens, rx) = mpsc::channel(1);
+        drop(rx);
+        match complete(&registry, Some(&tokens)).await {
+            Err(ApiError::Cancelled(_)) => {}
+            other => panic!("not cancelled: {:?}", other.map(|c| c.text)),
+        }
+    }
+}
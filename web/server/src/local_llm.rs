@@ -0,0 +1,239 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{bail, Error as E, Result};
+use api_types::Turn;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::mistral::Config;
+use candle_transformers::models::quantized_mistral::Model as QMistral;
+use candle_transformers::quantized_var_builder::VarBuilder;
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc;
+
+use crate::config::LocalLlmConfig;
+use crate::limits::CancelOnDrop;
+use crate::llm::client_gone;
+
+static LOCAL_MISTRAL: OnceLock<LocalMistral> = OnceLock::new();
+
+// Quantized Mistral-7B-Instruct running on the CPU. The model keeps a kv
+// cache, so generations are serialized behind the mutex.
+pub struct LocalMistral {
+    model: Mutex<QMistral>,
+    tokenizer: Tokenizer,
+    eos_token: u32,
+    device: Device,
+}
+
+impl LocalMistral {
+    pub fn load(config: &LocalLlmConfig) -> Result<Self> {
+        let device = Device::Cpu;
+        let api = Api::new()?;
+        let repo = api.repo(Repo::with_revision(
+            config.repo.clone(),
+            RepoType::Model,
+            "main".to_string(),
+        ));
+        let tokenizer_filename = repo.get("tokenizer.json")?;
+        let model_filename = repo.get(&config.file)?;
+
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let eos_token = tokenizer
+            .token_to_id("</s>")
+            .ok_or_else(|| E::msg("</s> is missing from the tokenizer"))?;
+        let vb = VarBuilder::from_gguf(model_filename)?;
+        let model = QMistral::new(&Config::config_7b_v0_1(false), vb)?;
+
+        Ok(Self {
+            model: Mutex::new(model),
+            tokenizer,
+            eos_token,
+            device,
+        })
+    }
+
+    // `prompt` is already in the instruct format, see `instruct_prompt`;
+    // generation stops with an error once `cancelled` is set
+    pub fn generate(
+        &self,
+        config: &LocalLlmConfig,
+        prompt: &str,
+        tokens: Option<&mpsc::Sender<String>>,
+        cancelled: &AtomicBool,
+    ) -> Result<String> {
+        let mut model = self.model.lock().map_err(|_| E::msg("local model lock"))?;
+        model.clear_kv_cache();
+
+        let mut all_tokens = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(E::msg)?
+            .get_ids()
+            .to_vec();
+        let prompt_len = all_tokens.len();
+        let mut logits_processor =
+            LogitsProcessor::new(config.seed, Some(config.temperature), config.top_p);
+
+        let mut text = String::new();
+        // bytes of `text` already sent to `tokens`
+        let mut sent = 0;
+        for index in 0..config.max_tokens {
+            if cancelled.load(Ordering::Relaxed) {
+                bail!("generation cancelled");
+            }
+            // the whole prompt first, then one token at a time on top of the kv cache
+            let context_size = if index > 0 { 1 } else { all_tokens.len() };
+            let start_pos = all_tokens.len().saturating_sub(context_size);
+            let input = Tensor::new(&all_tokens[start_pos..], &self.device)?.unsqueeze(0)?;
+            let logits = model.forward(&input, start_pos)?;
+            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+            let logits = if config.repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = all_tokens.len().saturating_sub(config.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    config.repeat_penalty,
+                    &all_tokens[start_at..],
+                )?
+            };
+
+            let next_token = logits_processor.sample(&logits)?;
+            if next_token == self.eos_token {
+                break;
+            }
+            all_tokens.push(next_token);
+
+            // decode everything generated so far, a token may end in the middle
+            // of a character so only complete text is used
+            let decoded = self
+                .tokenizer
+                .decode(&all_tokens[prompt_len..], true)
+                .map_err(E::msg)?;
+            if decoded.ends_with('\u{fffd}') {
+                continue;
+            }
+            text = decoded;
+            if let Some(end) = config
+                .stop
+                .iter()
+                .filter_map(|stop| text.find(stop.as_str()))
+                .min()
+            {
+                text.truncate(end);
+                break;
+            }
+            // text that may be the start of a stop sequence waits for the next tokens
+            let ready = stop_prefix_start(&text, &config.stop);
+            if let (Some(tokens), Some(piece)) = (tokens, text.get(sent..ready)) {
+                if !piece.is_empty() {
+                    if tokens.blocking_send(piece.to_string()).is_err() {
+                        return Err(client_gone());
+                    }
+                    sent = ready;
+                }
+            }
+        }
+        if let (Some(tokens), Some(rest)) = (tokens, text.get(sent..)) {
+            if !rest.is_empty() && tokens.blocking_send(rest.to_string()).is_err() {
+                return Err(client_gone());
+            }
+        }
+
+        Ok(text.trim().to_string())
+    }
+}
+
+// Where the longest end of `text` that could still grow into one of the
+// `stop` sequences starts, `text.len()` when there is none.
+fn stop_prefix_start(text: &str, stop: &[String]) -> usize {
+    text.char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| {
+            let tail = &text[i..];
+            stop.iter()
+                .any(|s| s.len() > tail.len() && s.starts_with(tail))
+        })
+        .unwrap_or(text.len())
+}
+
+// loaded on the first local generation
+pub fn local_mistral(config: &LocalLlmConfig) -> Result<&'static LocalMistral> {
+    if let Some(model) = LOCAL_MISTRAL.get() {
+        return Ok(model);
+    }
+    let model = LocalMistral::load(config)?;
+    let _ = LOCAL_MISTRAL.set(model);
+    Ok(LOCAL_MISTRAL.get().expect("local model is initialized"))
+}
+
+// Mistral instruct has no system role, the system prompt goes in front of the
+// first user message.
+pub fn instruct_prompt(system: &str, history: &[Turn], prompt: &str) -> String {
+    let mut turns = history
+        .iter()
+        .map(|turn| (turn.question.as_str(), Some(turn.answer.as_str())))
+        .collect::<Vec<_>>();
+    turns.push((prompt, None));
+
+    let mut out = String::from("<s>");
+    for (i, (question, answer)) in turns.into_iter().enumerate() {
+        if i == 0 && !system.is_empty() {
+            out.push_str(&format!("[INST] {}\n\n{} [/INST]", system, question));
+        } else {
+            out.push_str(&format!("[INST] {} [/INST]", question));
+        }
+        if let Some(answer) = answer {
+            out.push_str(&format!(" {}</s>", answer));
+        }
+    }
+    out
+}
+
+// Generate on a blocking thread, `tokens` gets the text as it is produced.
+// The thread stops at the next token when this future is dropped, on a
+// timeout or a cancelled request.
+pub async fn generate(
+    config: &LocalLlmConfig,
+    system: &str,
+    history: &[Turn],
+    prompt: &str,
+    tokens: Option<&mpsc::Sender<String>>,
+) -> Result<String> {
+    let config = config.clone();
+    let prompt = instruct_prompt(system, history, prompt);
+    let tokens = tokens.cloned();
+    let guard = CancelOnDrop::default();
+    let cancelled = guard.flag();
+    let text = tokio::task::spawn_blocking(move || -> Result<String> {
+        local_mistral(&config)?.generate(&config, &prompt, tokens.as_ref(), &cancelled)
+    })
+    .await?;
+    drop(guard);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_the_start_of_a_stop_sequence() {
+        let stop = vec!["[INST]".to_string()];
+        assert_eq!(stop_prefix_start("The answer.", &stop), 11);
+        assert_eq!(stop_prefix_start("The answer. [", &stop), 12);
+        assert_eq!(stop_prefix_start("The answer. [IN", &stop), 12);
+        // a bracket that can't start the stop any more goes out
+        assert_eq!(stop_prefix_start("see [1]", &stop), 7);
+        assert_eq!(stop_prefix_start("naïve", &stop), "naïve".len());
+    }
+
+    #[test]
+    fn the_longest_partial_stop_wins() {
+        let stop = vec!["</s>".to_string(), "\n\nQuestion:".to_string()];
+        assert_eq!(stop_prefix_start("done\n\nQue", &stop), 4);
+        assert_eq!(stop_prefix_start("done <", &stop), 5);
+    }
+}
@@ -1,30 +1,79 @@
+mod answer;
+mod audit;
+mod auth;
+mod browse;
+mod cache;
+mod chat;
+mod citations;
+mod config;
+mod context;
+mod diversify;
+mod embeddings;
+mod error;
+mod health;
+mod ingest;
+mod jobs;
+mod limits;
+mod llm;
+mod local_llm;
+mod metrics;
+mod openapi;
+mod prompts;
 mod query_qdrant_db;
+mod rerank;
+mod sessions;
+mod state;
+mod tokens;
+use crate::answer::{condense_question, generate_answer, model_name, resolve_prompt, AnswerKind};
+use crate::audit::AuditEntry;
+use crate::auth::Caller;
+use crate::cache::CachedAnswer;
+use crate::config::ServerConfig;
+use crate::context::{pack_context, PackedContext};
+use crate::error::ApiError;
+use crate::openapi::ApiDoc;
 use crate::query_qdrant_db::*;
+use crate::rerank::rerank;
+use crate::state::{AppState, SharedState};
+use api_types::{
+    Answer, ChatParams, ChunkPage, DocumentFormat, DocumentInfo, DocumentPage, DocumentRecord, ErrorBody,
+    ExportRange, Feedback, FeedbackRequest, Job, KeyUsage, LogRecord, Page, Pagination,
+    PromptTemplate, QueryText, Readiness, SearchMode, SectionInfo, SectionPage, ServerMessage,
+    Session, TokenEvent, Turn,
+};
 use axum::{
-    http::{Response, StatusCode},
-    routing::{get, post},
-    Json, Router,
+    extract::{
+        rejection::JsonRejection, ws::WebSocketUpgrade, DefaultBodyLimit, Multipart, Path, Query,
+        State,
+    },
+    http::{header, HeaderName, HeaderValue, Method, Response, StatusCode},
+    middleware,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
 use axum::body::Body;
 use clap::Parser;
-use llm_chain::{
-    chains::conversation::Chain, executor, output::Output, parameters, prompt, step::Step, options::{Options, ModelRef, OptionsBuilder},
-};
-use llm_chain_openai::chatgpt::Model;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
+use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::{
     net::{IpAddr, Ipv6Addr},
     path::PathBuf,
     str::FromStr,
+    time::{Duration, Instant},
 };
 use tokio::fs;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tower::{ServiceBuilder, ServiceExt};
 use tower_http::cors::Any;
 use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::OpenApi;
 
 #[derive(Parser, Debug)]
 #[clap(name = "server", about = "Experimental Server")]
@@ -40,11 +89,62 @@ struct Opt {
     /// set the directory where static files are to be found
     #[clap(long = "static-dir", default_value = "./dist")]
     static_dir: String,
+
+    /// TOML configuration file, `LLM_PLAYGROUND_*` environment variables override it
+    #[clap(long = "config")]
+    config: Option<String>,
+
+    /// print the OpenAPI document of the API and exit
+    #[clap(long = "openapi")]
+    openapi: bool,
+
+    /// create an API key with this name in `auth.key_db`, print it and exit
+    #[clap(long = "add-api-key")]
+    add_api_key: Option<String>,
+
+    /// with --add-api-key, the key may export every key's logs, clear the
+    /// answer cache, upload documents and cancel ingestion jobs
+    #[clap(long = "admin")]
+    admin: bool,
+
+    /// delete the API key with this name from `auth.key_db` and exit
+    #[clap(long = "revoke-api-key")]
+    revoke_api_key: Option<String>,
+}
+
+// browsers may only call the API from `cors_origins`
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            HeaderName::from_static("x-api-key"),
+        ]);
+    if origins.iter().any(|o| o == "*") {
+        cors.allow_origin(Any)
+    } else {
+        cors.allow_origin(
+            origins
+                .iter()
+                .filter_map(|o| o.parse::<HeaderValue>().ok())
+                .collect::<Vec<_>>(),
+        )
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let opt = Opt::parse();
+    if opt.openapi {
+        println!(
+            "{}",
+            ApiDoc::openapi()
+                .to_pretty_json()
+                .expect("openapi serialization")
+        );
+        return;
+    }
 
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
@@ -54,27 +154,118 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let config = ServerConfig::load(opt.config.as_deref()).expect("invalid configuration");
+    if opt.add_api_key.is_some() || opt.revoke_api_key.is_some() {
+        let auth = auth::Auth::open(&config.auth)
+            .await
+            .expect("can't open the API key database");
+        if let Some(name) = &opt.add_api_key {
+            let key = auth
+                .add_key(name, opt.admin)
+                .await
+                .expect("can't add the API key");
+            println!("{}", key);
+        }
+        if let Some(name) = &opt.revoke_api_key {
+            auth.revoke_key(name).await.expect("can't revoke the API key");
+        }
+        return;
+    }
+    let state: SharedState = Arc::new(
+        AppState::new(config)
+            .await
+            .expect("failed to initialize the application state"),
+    );
+
+    tokio::spawn(jobs::run_worker(state.clone()));
+    tokio::spawn(cache::watch_index(state.clone()));
+
+    let grace = Duration::from_secs(state.config.limits.shutdown_grace_secs);
+    let ip = IpAddr::from_str(opt.addr.as_str()).unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    if !ip.is_loopback() && !state.config.auth.enabled {
+        tracing::warn!(
+            "listening on {} without API keys, anyone who can reach it can use the models",
+            ip
+        );
+    }
+    let config = &state.config;
+    let has_local_model = config.models.iter().any(|m| m.backend == config::Backend::Local);
+    if has_local_model && !config.embeddings_are_local() {
+        tracing::warn!(
+            "questions to local models are still embedded by {}, set local.offline to refuse it",
+            config.embedding_base_url()
+        );
+    }
+
     // build our application with a route
-    let app = Router::new()
+    let upload_limit = state.config.max_upload_mb * 1024 * 1024;
+    let api = Router::new()
         .route(
-            "/api/post_query_for_similarity_search",
+            "/post_query_for_similarity_search",
             post(post_query_for_similarity_search),
         )
         .route(
-            "/api/post_query_for_answer_of_a_question",
+            "/post_query_for_answer_of_a_question",
             post(post_query_for_answer_of_a_question),
         )
         .route(
-            "/api/post_query_for_summary_of_a_topic",
+            "/post_query_for_summary_of_a_topic",
             post(post_query_for_summary_of_a_topic),
         )
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                //.allow_origin("http://127.0.0.1:8080".parse::<HeaderValue>().unwrap())
-                .allow_methods(Any)
-                .allow_headers(Any),
+        .route(
+            "/stream_query_for_answer_of_a_question",
+            post(stream_query_for_answer_of_a_question),
+        )
+        .route(
+            "/stream_query_for_summary_of_a_topic",
+            post(stream_query_for_summary_of_a_topic),
+        )
+        .route(
+            "/documents",
+            get(get_documents)
+                .post(upload_document)
+                .layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/documents/:document_id/sections", get(get_sections))
+        .route(
+            "/documents/:document_id/sections/:section_id/chunks",
+            get(get_section_chunks),
+        )
+        .route(
+            "/documents/:document_id/sections/:section_id/chunks/:chunk_id",
+            get(get_chunk),
+        )
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:job_id", get(get_job))
+        .route("/jobs/:job_id/cancel", post(cancel_job))
+        .route("/prompts", get(list_prompts))
+        .route("/feedback", post(post_feedback))
+        .route("/logs/export", get(export_logs))
+        .route("/sessions", post(create_session))
+        .route(
+            "/sessions/:session_id",
+            get(get_session).delete(delete_session),
         )
+        .route("/usage", get(get_usage))
+        .route("/cache", delete(clear_cache))
+        .route("/ws", get(chat_socket))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        // outside of `authenticate`, so that refused keys are counted
+        .route_layer(middleware::from_fn(metrics::track));
+
+    // `/api/v1` is the documented API, `/api` the unversioned routes it
+    // started from, kept for existing clients
+    let app = Router::new()
+        .route("/api/openapi.json", get(openapi_json))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(get_metrics))
+        .nest("/api/v1", api.clone())
+        .nest("/api", api)
+        .layer(cors_layer(&state.config.cors_origins))
         .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()))
         .fallback(get(|req| async move {
             match ServeDir::new(&opt.static_dir).oneshot(req).await {
@@ -107,168 +298,937 @@ async fn main() {
                     .body(Body::from("internal errors"))
                     .expect("error response"),
             }
-        }));
+        }))
+        .with_state(state);
 
     // run it
-    let addr = SocketAddr::from((
-         IpAddr::from_str(opt.addr.as_str()).unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST)),
-         opt.port,
-    ));
+    let addr = SocketAddr::from((ip, opt.port));
     let listener = tokio::net::TcpListener::bind(addr)
     .await
     .unwrap();
-    println!("listening on {}", addr);
-    axum::serve(listener, app)
-        .await
-        .unwrap();
+    tracing::info!("listening on {}", addr);
+    // on a signal new connections are refused and the open ones drained,
+    // for at most the grace period: streams and sockets can run for long
+    let stopping = Arc::new(Notify::new());
+    let server = axum::serve(listener, app).with_graceful_shutdown({
+        let stopping = stopping.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::info!(
+                "shutting down, waiting up to {}s for the requests in flight",
+                grace.as_secs()
+            );
+            stopping.notify_one();
+        }
+    });
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            stopping.notified().await;
+            tokio::time::sleep(grace).await;
+        } => {
+            tracing::warn!("requests still running after {}s, stopping anyway", grace.as_secs());
+        }
+    }
+}
+
+// Ctrl-C, or the SIGTERM of systemd and container runtimes
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("can't listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct QueryText {
+async fn search(
+    state: &AppState,
+    query: &QueryText,
+    vector: Vec<f32>,
     topn: u64,
-    text: String,
+) -> Result<Vec<DocumentRecord>, ApiError> {
+    let (diversity, filters) = (&query.diversity, &query.filters);
+    match query.mode {
+        SearchMode::Chunks => query_for_chunks(state, vector, topn, diversity, filters).await,
+        SearchMode::Sections => query_for_sections(state, vector, topn, diversity, filters).await,
+        SearchMode::Hierarchical => {
+            let topk = HierarchicalTopK {
+                documents: query.top_documents.unwrap_or(5),
+                sections: query.top_sections.unwrap_or(10),
+                chunks: topn,
+            };
+            query_hierarchical(state, vector, topk, diversity, filters).await
+        }
+    }
 }
 
-async fn post_query_for_similarity_search(
-    Json(query): Json<QueryText>,
-) -> Json<Option<Vec<DocumentRecord>>> {
-    let return_docs = query_for_sections(&query.text, query.topn);
-    match return_docs.await {
-        Ok(r) => Json(Some(r)),
-        _ => Json(None),
+fn validate(query: &QueryText) -> Result<(), ApiError> {
+    if query.text.trim().is_empty() {
+        return Err(ApiError::BadRequest("the query text is empty".to_string()));
+    }
+    if query.topn == 0 || query.topn > 100 {
+        return Err(ApiError::BadRequest("topn must be between 1 and 100".to_string()));
+    }
+    if query.rerank_candidates.unwrap_or_default() > 200 {
+        return Err(ApiError::BadRequest(
+            "rerank_candidates must be at most 200".to_string(),
+        ));
     }
+    query.generation.validate().map_err(ApiError::BadRequest)
 }
 
-async fn post_query_for_answer_of_a_question(
-    Json(query): Json<QueryText>,
-) -> Json<Option<Vec<DocumentRecord>>> {
-    let docs = query_for_sections(&query.text, query.topn).await;
-
-    let context = if let Ok(records) = docs {
-        records
-            .iter()
-            .map(|record| {
-                let record = record.clone();
-                let mut out_strings = Vec::<String>::new();
-                if record.keywords.is_some() {
-                    out_strings.push(format!("KEYWORDS: {}", record.keywords.unwrap()))
-                };
-                if record.url.is_some() {
-                    out_strings.push(format!("URL: {}", record.url.unwrap()))
-                };
-                if record.text.is_some() {
-                    out_strings.push(format!("CONTENT: {}", record.text.unwrap()))
-                };
-                out_strings.join("\n")
-            })
-            .collect::<Vec<_>>()
-            .join("\n")
-    } else {
-        "".to_string()
+// `text` is what gets searched for, the query text itself or the standalone
+// version of a follow-up question; embedding it is counted against `api_key`
+async fn retrieve(
+    state: &AppState,
+    query: &QueryText,
+    text: &str,
+    api_key: Option<&str>,
+) -> Result<Vec<DocumentRecord>, ApiError> {
+    validate(query)?;
+    tracing::debug!("searching for {}", text);
+    let vector = state.embed(text, api_key).await?;
+    match query.rerank_candidates {
+        Some(candidates) if candidates > query.topn => {
+            let docs = search(state, query, vector, candidates).await?;
+            let reranked = rerank(&state.config.reranker_model, text, docs, query.topn);
+            state
+                .limits
+                .rerank
+                .run(async { reranked.await.map_err(ApiError::internal) })
+                .await
+        }
+        _ => search(state, query, vector, query.topn).await,
+    }
+}
+
+// the retrieval side of an answer, with the session history if there is one
+struct Grounding {
+    template: PromptTemplate,
+    history: Vec<Turn>,
+    standalone_query: String,
+    context: PackedContext,
+    /// where the answer goes in the cache, when it can be cached
+    cache_key: Option<cache::CacheKey>,
+}
+
+enum Grounded {
+    Cached(CachedAnswer),
+    Fresh(Grounding),
+}
+
+async fn ground(
+    state: &AppState,
+    query: &QueryText,
+    kind: AnswerKind,
+    api_key: Option<&str>,
+) -> Result<Grounded, ApiError> {
+    validate(query)?;
+    let model = model_name(state, &query.generation);
+    if !state.llms.contains(model) {
+        return Err(ApiError::BadRequest(format!("unknown model {}", model)));
+    }
+    let template = resolve_prompt(state, kind, query.prompt.as_deref())?.clone();
+    let history = match &query.session_id {
+        Some(session_id) => {
+            state
+                .sessions
+                .history(session_id, state.config.history_turns)
+                .await?
+        }
+        None => vec![],
     };
+    let mut cache_key = None;
+    if state.cache.enabled() && history.is_empty() {
+        let mut key = state.cache.key(kind, model, &template.id(), query);
+        if let Some(cached) = cache::lookup(state, &mut key, &query.text, api_key).await? {
+            return Ok(Grounded::Cached(cached));
+        }
+        cache_key = Some(key);
+    }
+    let standalone_query =
+        condense_question(state, &query.generation, &history, &query.text, api_key).await?;
+    let docs = retrieve(state, query, &standalone_query, api_key).await?;
+    let max_prompt_tokens = state.config.max_prompt_tokens;
+    let context = pack_context(max_prompt_tokens, model, &template, &query.text, &history, docs)?;
+    Ok(Grounded::Fresh(Grounding {
+        template,
+        history,
+        standalone_query,
+        context,
+        cache_key,
+    }))
+}
 
-    if context.is_empty() {
-        return Json(None);
+async fn record_turn(
+    state: &AppState,
+    query: &QueryText,
+    standalone_query: &str,
+    answer: &Answer,
+) -> Result<(), ApiError> {
+    if let Some(session_id) = &query.session_id {
+        state
+            .sessions
+            .add_turn(session_id, &query.text, standalone_query, &answer.text)
+            .await?;
     }
+    Ok(())
+}
 
-    
-    let model = ModelRef::from_model_name(Model::Gpt35Turbo.to_string());
-    let mut options =  OptionsBuilder::new();
-    options.add_option(llm_chain::options::Opt::Model(model));
-    let options = options.build();
-    let exec = executor!(chatgpt, options).unwrap();
-
-    let mut chain =
-        Chain::new(prompt!(system: "You are an assistant for making scientific recommendations."))
-            .unwrap();
-    dbg!(&context);
-
-    let res = chain
-        .send_message(
-            Step::for_prompt_template(prompt!(user: "given the context below\n: CONTEXT: {{context}} \n\n answer the question: {{question}}")), 
-            // Create a Parameters object with key-value pairs for the placeholders
-            &parameters!("context" => &context[..],
-                         "question" => &query.text[..]),
-            &exec,
-        )
-        .await
-        .unwrap().to_immediate().await.unwrap();
+// a cached answer to a first question, logged and added to the session like
+// a generated one
+async fn serve_cached(
+    state: &AppState,
+    query: &QueryText,
+    cached: &CachedAnswer,
+    entry: &mut AuditEntry,
+    start: Instant,
+) -> Result<Answer, ApiError> {
+    let answer = cached.served(start);
+    entry.grounded(&query.text, answer.prompt.clone(), &cached.records);
+    entry.answered(&answer);
+    record_turn(state, query, &query.text, &answer).await?;
+    Ok(answer)
+}
+
+fn cache_answer(state: &AppState, grounding: Grounding, answer: &Answer) {
+    if let Some(key) = grounding.cache_key {
+        state.cache.insert(key, answer, &grounding.context.records);
+    }
+}
+
+fn json_body(payload: Result<Json<QueryText>, JsonRejection>) -> Result<QueryText, ApiError> {
+    let Json(query) = payload.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    Ok(query)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/post_query_for_similarity_search",
+    tag = "search",
+    request_body = QueryText,
+    responses(
+        (status = 200, description = "the closest chunks or sections", body = [DocumentRecord]),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+        (status = 504, description = "the embedding or the search took longer than its timeout", body = ErrorBody),
+    )
+)]
+async fn post_query_for_similarity_search(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<QueryText>, JsonRejection>,
+) -> Result<Json<Vec<DocumentRecord>>, ApiError> {
+    let query = json_body(payload)?;
+    auth::check_quota(&state, &caller).await?;
+    let mut entry = AuditEntry::new("similarity_search", &query.text, None, caller.key_name());
+    let result = retrieve(&state, &query, &query.text, caller.key_name()).await;
+    if let Ok(docs) = &result {
+        entry.retrieved(docs);
+    }
+    state.audit.record(entry, result.as_ref().err()).await;
+    Ok(Json(result?))
+}
 
-    let text = res.primary_textual_output();
-    let r = DocumentRecord {
-        text,
-        ..Default::default()
+// ground the query, generate the answer (unless it is cached) and add it to
+// the session; the steps are written to `entry` as they complete
+async fn answer_query(
+    state: &AppState,
+    query: &QueryText,
+    kind: AnswerKind,
+    entry: &mut AuditEntry,
+) -> Result<Answer, ApiError> {
+    let start = Instant::now();
+    let grounding = match ground(state, query, kind, entry.api_key()).await? {
+        Grounded::Fresh(grounding) => grounding,
+        Grounded::Cached(cached) => {
+            return serve_cached(state, query, &cached, entry, start).await;
+        }
     };
-    Json(Some(vec![r]))
+    entry.grounded(
+        &grounding.standalone_query,
+        grounding.template.id(),
+        &grounding.context.records,
+    );
+    let answer = generate_answer(
+        state,
+        &query.generation,
+        &grounding.template,
+        &query.text,
+        &grounding.context,
+        &grounding.history,
+        None,
+    )
+    .await?;
+    entry.answered(&answer);
+    record_turn(state, query, &grounding.standalone_query, &answer).await?;
+    cache_answer(state, grounding, &answer);
+    Ok(answer)
 }
 
+async fn answer_response(
+    state: SharedState,
+    caller: Caller,
+    payload: Result<Json<QueryText>, JsonRejection>,
+    kind: AnswerKind,
+    endpoint: &'static str,
+) -> Result<Json<Answer>, ApiError> {
+    let query = json_body(payload)?;
+    auth::check_quota(&state, &caller).await?;
+    let mut entry = AuditEntry::new(
+        endpoint,
+        &query.text,
+        query.session_id.as_deref(),
+        caller.key_name(),
+    );
+    let result = answer_query(&state, &query, kind, &mut entry).await;
+    let log_id = state.audit.record(entry, result.as_ref().err()).await;
+    let mut answer = result?;
+    answer.log_id = log_id;
+    Ok(Json(answer))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/post_query_for_answer_of_a_question",
+    tag = "answer",
+    request_body = QueryText,
+    responses(
+        (status = 200, description = "the answer with its sources", body = Answer),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 502, description = "the LLM or embedding provider failed", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+        (status = 504, description = "a stage of the answer took longer than its timeout", body = ErrorBody),
+    )
+)]
+async fn post_query_for_answer_of_a_question(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<QueryText>, JsonRejection>,
+) -> Result<Json<Answer>, ApiError> {
+    answer_response(
+        state,
+        caller,
+        payload,
+        AnswerKind::Question,
+        "answer_of_a_question",
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/post_query_for_summary_of_a_topic",
+    tag = "answer",
+    request_body = QueryText,
+    responses(
+        (status = 200, description = "the summary with its sources", body = Answer),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 502, description = "the LLM or embedding provider failed", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+        (status = 504, description = "a stage of the answer took longer than its timeout", body = ErrorBody),
+    )
+)]
 async fn post_query_for_summary_of_a_topic(
-    Json(query): Json<QueryText>,
-) -> Json<Option<Vec<DocumentRecord>>> {
-    let docs = query_for_sections(&query.text, query.topn).await;
-
-    let context = if let Ok(records) = docs {
-        records
-            .iter()
-            .map(|record| {
-                let record = record.clone();
-                let mut out_strings = Vec::<String>::new();
-                if record.keywords.is_some() {
-                    out_strings.push(format!("KEYWORDS: {}", record.keywords.unwrap()))
-                };
-                if record.url.is_some() {
-                    out_strings.push(format!("URL: {}", record.url.unwrap()))
-                };
-                if record.text.is_some() {
-                    out_strings.push(format!("CONTENT: {}", record.text.unwrap()))
-                };
-                out_strings.join("\n")
-            })
-            .collect::<Vec<_>>()
-            .join("\n")
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<QueryText>, JsonRejection>,
+) -> Result<Json<Answer>, ApiError> {
+    answer_response(state, caller, payload, AnswerKind::Summary, "summary_of_a_topic").await
+}
+
+fn sse_event(name: &str, data: &impl Serialize) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(data)
+        .expect("sse event serialization")
+}
+
+// `sources` (the retrieved records, in context order), then `token` messages
+// as the answer is generated; the caller sends `done` once the answer is
+// logged. Generation stops when `events` is closed, and the request fails
+// with `Cancelled`.
+async fn stream_answer(
+    state: &AppState,
+    query: &QueryText,
+    kind: AnswerKind,
+    entry: &mut AuditEntry,
+    events: &mpsc::Sender<ServerMessage>,
+) -> Result<Answer, ApiError> {
+    let start = Instant::now();
+    let grounding = match ground(state, query, kind, entry.api_key()).await? {
+        Grounded::Fresh(grounding) => grounding,
+        Grounded::Cached(cached) => {
+            // the whole answer as a single token
+            let answer = serve_cached(state, query, &cached, entry, start).await?;
+            let records = cached.records;
+            let _ = events.send(ServerMessage::Sources { records }).await;
+            let text = answer.text.clone();
+            let _ = events.send(ServerMessage::Token { text }).await;
+            return Ok(answer);
+        }
+    };
+    entry.grounded(
+        &grounding.standalone_query,
+        grounding.template.id(),
+        &grounding.context.records,
+    );
+    let records = grounding.context.records.clone();
+    let _ = events.send(ServerMessage::Sources { records }).await;
+
+    let (tokens, mut token_rx) = mpsc::channel::<String>(64);
+    let token_events = events.clone();
+    // whether the client went away before the last token
+    let forward = tokio::spawn(async move {
+        while let Some(text) = token_rx.recv().await {
+            if token_events.send(ServerMessage::Token { text }).await.is_err() {
+                return true;
+            }
+        }
+        false
+    });
+
+    let answer = generate_answer(
+        state,
+        &query.generation,
+        &grounding.template,
+        &query.text,
+        &grounding.context,
+        &grounding.history,
+        Some(&tokens),
+    )
+    .await;
+    drop(tokens);
+    let gone = forward.await.unwrap_or(false);
+
+    let answer = answer?;
+    // a partial answer is neither a turn of the session nor worth caching
+    if gone || events.is_closed() {
+        return Err(ApiError::Cancelled("the client went away".to_string()));
+    }
+    entry.answered(&answer);
+    record_turn(state, query, &grounding.standalone_query, &answer).await?;
+    cache_answer(state, grounding, &answer);
+    Ok(answer)
+}
+
+// `done` with the logged answer, or the error
+fn final_message(result: Result<Answer, ApiError>, log_id: Option<i64>) -> ServerMessage {
+    match result {
+        Ok(mut answer) => {
+            answer.log_id = log_id;
+            ServerMessage::Done {
+                answer: Box::new(answer),
+            }
+        }
+        Err(ApiError::Cancelled(_)) => ServerMessage::Cancelled,
+        Err(e) => {
+            // the response went out as 200, `into_response` doesn't see it
+            metrics::count_error(e.code());
+            ServerMessage::Error { error: e.body() }
+        }
+    }
+}
+
+// the payloads of the WebSocket messages, as named events
+fn sse_message(message: ServerMessage) -> Event {
+    match message {
+        ServerMessage::Sources { records } => sse_event("sources", &records),
+        ServerMessage::Token { text } => sse_event("token", &TokenEvent { text }),
+        ServerMessage::Done { answer } => sse_event("done", &answer),
+        ServerMessage::Error { error } => sse_event("error", &error),
+        // not sent on a stream
+        other => sse_event("message", &other),
+    }
+}
+
+// `done` carries the complete answer with its checked citations, token usage
+// and log id; `error` replaces the rest on failure
+async fn sse_response(
+    state: SharedState,
+    caller: Caller,
+    payload: Result<Json<QueryText>, JsonRejection>,
+    kind: AnswerKind,
+    endpoint: &'static str,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let query = json_body(payload)?;
+    validate(&query)?;
+    auth::check_quota(&state, &caller).await?;
+    let (events, events_rx) = mpsc::channel::<ServerMessage>(64);
+    tokio::spawn(async move {
+        let mut entry = AuditEntry::new(
+            endpoint,
+            &query.text,
+            query.session_id.as_deref(),
+            caller.key_name(),
+        );
+        let result = stream_answer(&state, &query, kind, &mut entry, &events).await;
+        let log_id = state.audit.record(entry, result.as_ref().err()).await;
+        let _ = events.send(final_message(result, log_id)).await;
+    });
+    let events = ReceiverStream::new(events_rx).map(|message| Ok(sse_message(message)));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/stream_query_for_answer_of_a_question",
+    tag = "answer",
+    request_body = QueryText,
+    responses(
+        (status = 200, description = "`sources`, `token`, then `done` or `error` events", content_type = "text/event-stream", body = TokenEvent),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+    )
+)]
+async fn stream_query_for_answer_of_a_question(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<QueryText>, JsonRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    sse_response(
+        state,
+        caller,
+        payload,
+        AnswerKind::Question,
+        "stream_answer_of_a_question",
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/stream_query_for_summary_of_a_topic",
+    tag = "answer",
+    request_body = QueryText,
+    responses(
+        (status = 200, description = "`sources`, `token`, then `done` or `error` events", content_type = "text/event-stream", body = TokenEvent),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+    )
+)]
+async fn stream_query_for_summary_of_a_topic(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<QueryText>, JsonRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    sse_response(
+        state,
+        caller,
+        payload,
+        AnswerKind::Summary,
+        "stream_summary_of_a_topic",
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "a new empty session", body = Session),
+    )
+)]
+async fn create_session(State(state): State<SharedState>) -> Result<Json<Session>, ApiError> {
+    Ok(Json(state.sessions.create().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/{session_id}",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "id returned by `POST /api/v1/sessions`")),
+    responses(
+        (status = 200, description = "the session with its turns", body = Session),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn get_session(
+    State(state): State<SharedState>,
+    Path(session_id): Path<String>,
+) -> Result<Json<Session>, ApiError> {
+    Ok(Json(state.sessions.get(&session_id).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/sessions/{session_id}",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "id returned by `POST /api/v1/sessions`")),
+    responses(
+        (status = 204, description = "the session is deleted"),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn delete_session(
+    State(state): State<SharedState>,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    state.sessions.delete(&session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/prompts",
+    tag = "prompts",
+    responses(
+        (status = 200, description = "every version of every template", body = [PromptTemplate]),
+    )
+)]
+async fn list_prompts(State(state): State<SharedState>) -> Json<Vec<PromptTemplate>> {
+    Json(state.prompts.list())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/feedback",
+    tag = "logs",
+    request_body = FeedbackRequest,
+    responses(
+        (status = 200, description = "the stored feedback", body = Feedback),
+        (status = 404, description = "no logged request of the caller's key unless it is an admin", body = ErrorBody),
+    )
+)]
+async fn post_feedback(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    payload: Result<Json<FeedbackRequest>, JsonRejection>,
+) -> Result<Json<Feedback>, ApiError> {
+    let Json(feedback) = payload.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let only = if caller.is_admin() {
+        None
     } else {
-        "".to_string()
+        caller.key_name()
     };
+    Ok(Json(state.audit.add_feedback(&feedback, only).await?))
+}
 
-    if context.is_empty() {
-        return Json(None);
+// the logged requests with their feedback as JSON lines, `?since=&until=` in
+// unix seconds
+#[utoipa::path(
+    get,
+    path = "/api/v1/logs/export",
+    tag = "logs",
+    params(ExportRange),
+    responses(
+        (status = 200, description = "one logged request per line, only the caller's unless it is an admin", content_type = "application/x-ndjson", body = LogRecord),
+    )
+)]
+async fn export_logs(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    Query(range): Query<ExportRange>,
+) -> Result<Response<Body>, ApiError> {
+    let only = if caller.is_admin() {
+        None
+    } else {
+        caller.key_name()
+    };
+    let mut lines = String::new();
+    for record in state.audit.export(&range, only).await? {
+        lines.push_str(&serde_json::to_string(&record).map_err(ApiError::internal)?);
+        lines.push('\n');
     }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from(lines))
+        .map_err(ApiError::internal)
+}
 
-    let model = ModelRef::from_model_name(Model::Gpt35Turbo.to_string());
-    let mut options =  OptionsBuilder::new();
-    options.add_option(llm_chain::options::Opt::Model(model));
-    let options = options.build();
-    let exec = executor!(chatgpt, options).unwrap();
-    
-    let mut chain =
-        Chain::new(prompt!(system: "You are an assistant for making scientific recommendations."))
-            .unwrap();
-    dbg!(&context);
-
-    let res = chain
-        .send_message(
-            Step::for_prompt_template(prompt!(user: "given the context below\n: CONTEXT: {{context}} \n\n Please write an essay about the topic {{topic}} with evidences and references")), 
-            // Create a Parameters object with key-value pairs for the placeholders
-            &parameters!("context" => &context[..],
-                         "topic" => &query.text[..]),
-            &exec,
-        )
+#[utoipa::path(
+    get,
+    path = "/api/v1/documents",
+    tag = "documents",
+    params(Pagination),
+    responses(
+        (status = 200, description = "the indexed documents", body = DocumentPage),
+        (status = 400, description = "invalid request", body = ErrorBody),
+    )
+)]
+async fn get_documents(
+    State(state): State<SharedState>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Page<DocumentInfo>>, ApiError> {
+    pagination.validate().map_err(ApiError::BadRequest)?;
+    Ok(Json(browse::list_documents(&state, &pagination).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/documents/{document_id}/sections",
+    tag = "documents",
+    params(("document_id" = usize, Path, description = "`document_id` of the document"), Pagination),
+    responses(
+        (status = 200, description = "the sections of the document", body = SectionPage),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn get_sections(
+    State(state): State<SharedState>,
+    Path(document_id): Path<usize>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Page<SectionInfo>>, ApiError> {
+    pagination.validate().map_err(ApiError::BadRequest)?;
+    Ok(Json(
+        browse::list_sections(&state, document_id, &pagination).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/documents/{document_id}/sections/{section_id}/chunks",
+    tag = "documents",
+    params(("document_id" = usize, Path, description = "`document_id` of the document"), ("section_id" = usize, Path, description = "`section_id` within the document"), Pagination),
+    responses(
+        (status = 200, description = "the chunks of the section, in order", body = ChunkPage),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn get_section_chunks(
+    State(state): State<SharedState>,
+    Path((document_id, section_id)): Path<(usize, usize)>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Page<DocumentRecord>>, ApiError> {
+    pagination.validate().map_err(ApiError::BadRequest)?;
+    Ok(Json(
+        browse::section_chunks(&state, document_id, section_id, &pagination).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/documents/{document_id}/sections/{section_id}/chunks/{chunk_id}",
+    tag = "documents",
+    params(("document_id" = usize, Path, description = "`document_id` of the document"), ("section_id" = usize, Path, description = "`section_id` within the document"), ("chunk_id" = usize, Path, description = "`chunk_id` within the section")),
+    responses(
+        (status = 200, description = "the chunk", body = DocumentRecord),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn get_chunk(
+    State(state): State<SharedState>,
+    Path((document_id, section_id, chunk_id)): Path<(usize, usize, usize)>,
+) -> Result<Json<DocumentRecord>, ApiError> {
+    Ok(Json(
+        browse::fetch_chunk(&state, document_id, section_id, chunk_id).await?,
+    ))
+}
+
+// the multipart body of `upload_document`, for the OpenAPI document only
+#[derive(utoipa::ToSchema)]
+#[allow(dead_code)]
+struct UploadForm {
+    /// an `.nxml`, `.pdf` or `.md` file
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+// multipart upload of an NXML, PDF or Markdown `file`, parsed, embedded and
+// added to the collections by a background job
+#[utoipa::path(
+    post,
+    path = "/api/v1/documents",
+    tag = "documents",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "the queued ingestion job", body = Job),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 403, description = "needs an admin API key", body = ErrorBody),
+        (status = 429, description = "rate limit or daily quota exceeded", body = ErrorBody),
+        (status = 503, description = "too many uploads waiting to be indexed", body = ErrorBody),
+    )
+)]
+async fn upload_document(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    caller.require_admin()?;
+    auth::check_quota(&state, &caller).await?;
+    while let Some(field) = multipart
+        .next_field()
         .await
-        .unwrap().to_immediate().await.unwrap();
-
-    //dbg!(res);
-
-    let text = res.primary_textual_output();
-    let r = DocumentRecord {
-        score: None,
-        file_name: None,
-        url: None,
-        document_id: None,
-        section_id: None,
-        chunk_id: None,
-        keywords: None,
-        text,
-        ..Default::default()
+        .map_err(|e| ApiError::BadRequest(e.body_text()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let file_name = field
+            .file_name()
+            .and_then(jobs::upload_file_name)
+            .ok_or_else(|| ApiError::BadRequest("the file has no name".to_string()))?;
+        let format = DocumentFormat::from_file_name(&file_name).ok_or_else(|| {
+            ApiError::BadRequest(format!("{} is not an .nxml, .pdf or .md file", file_name))
+        })?;
+        if ingest::find_document(&state, &file_name).await?.is_some() {
+            return Err(ApiError::BadRequest(format!(
+                "{} is already indexed",
+                file_name
+            )));
+        }
+        let upload = field
+            .bytes()
+            .await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        let job = state
+            .jobs
+            .submit(&file_name, format, upload.to_vec(), caller.key_name())?;
+        return Ok((StatusCode::ACCEPTED, Json(job)));
+    }
+    Err(ApiError::BadRequest("the upload has no `file` field".to_string()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs",
+    tag = "jobs",
+    responses(
+        (status = 200, description = "the jobs since the server started", body = [Job]),
+    )
+)]
+async fn list_jobs(State(state): State<SharedState>) -> Json<Vec<Job>> {
+    Json(state.jobs.list())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{job_id}",
+    tag = "jobs",
+    params(("job_id" = u64, Path, description = "id returned by the upload")),
+    responses(
+        (status = 200, description = "the job", body = Job),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn get_job(
+    State(state): State<SharedState>,
+    Path(job_id): Path<u64>,
+) -> Result<Json<Job>, ApiError> {
+    Ok(Json(state.jobs.get(job_id)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/jobs/{job_id}/cancel",
+    tag = "jobs",
+    params(("job_id" = u64, Path, description = "id returned by the upload")),
+    responses(
+        (status = 200, description = "the job, cancelled or about to be", body = Job),
+        (status = 400, description = "invalid request", body = ErrorBody),
+        (status = 403, description = "needs an admin API key", body = ErrorBody),
+        (status = 404, description = "not found", body = ErrorBody),
+    )
+)]
+async fn cancel_job(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    Path(job_id): Path<u64>,
+) -> Result<Json<Job>, ApiError> {
+    caller.require_admin()?;
+    Ok(Json(state.jobs.cancel(job_id)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/usage",
+    tag = "usage",
+    responses(
+        (status = 200, description = "what the calling key spent today and its limits", body = KeyUsage),
+    )
+)]
+async fn get_usage(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<KeyUsage>, ApiError> {
+    Ok(Json(auth::usage_today(&state, &caller).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/ws",
+    tag = "answer",
+    params(
+        ("session_id" = Option<String>, Query, description = "continue a conversation, a new session is created without it"),
+        ("api_key" = Option<String>, Query, description = "for browsers, which can't set headers on a WebSocket"),
+    ),
+    responses(
+        (status = 101, description = "a WebSocket carrying `ClientMessage`s and `ServerMessage`s as JSON text"),
+        (status = 404, description = "the session is not found", body = ErrorBody),
+    )
+)]
+async fn chat_socket(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+    Query(params): Query<ChatParams>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response<Body>, ApiError> {
+    let session = match &params.session_id {
+        Some(session_id) => state.sessions.get(session_id).await?,
+        None => state.sessions.create().await?,
+    };
+    Ok(upgrade.on_upgrade(move |socket| chat::run(socket, state, caller, session.session_id)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/cache",
+    tag = "answer",
+    responses(
+        (status = 204, description = "the cached answers are dropped"),
+        (status = 403, description = "needs an admin API key", body = ErrorBody),
+    )
+)]
+async fn clear_cache(
+    State(state): State<SharedState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<StatusCode, ApiError> {
+    caller.require_admin()?;
+    state.cache.clear("cleared through the API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    security(()),
+    responses(
+        (status = 200, description = "every dependency answered", body = Readiness),
+        (status = 503, description = "some dependency is down, see `checks`", body = Readiness),
+    )
+)]
+async fn readyz(State(state): State<SharedState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = health::readiness(&state).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
     };
-    Json(Some(vec![r]))
+    (status, Json(readiness))
+}
+
+async fn get_metrics() -> ([(HeaderName, &'static str); 1], String) {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
 }
@@ -0,0 +1,146 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+// generation can take most of a minute, the default buckets stop at 10s
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.,
+];
+
+// The Prometheus series of `/metrics`. Global like the tokenizers and the
+// models, so that errors and stages can be counted wherever they happen.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_seconds: HistogramVec,
+    stage_seconds: HistogramVec,
+    tokens: IntCounterVec,
+    errors: IntCounterVec,
+    cache_lookups: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("valid metric definitions"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("llm_playground".to_string()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "API requests by route and status"),
+            &["method", "route", "status"],
+        )?;
+        let request_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "time to the response headers, streams run on after it",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["method", "route"],
+        )?;
+        let stage_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "stage_duration_seconds",
+                "time spent in embed, search, rerank and generate",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["stage"],
+        )?;
+        let tokens = IntCounterVec::new(
+            Opts::new(
+                "llm_tokens_total",
+                "prompt and completion tokens of the answers",
+            ),
+            &["model", "kind"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("api_errors_total", "failed requests by error code"),
+            &["code"],
+        )?;
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("answer_cache_lookups_total", "answer cache hits and misses"),
+            &["result"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_seconds.clone()))?;
+        registry.register(Box::new(stage_seconds.clone()))?;
+        registry.register(Box::new(tokens.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(cache_lookups.clone()))?;
+        Ok(Self {
+            registry,
+            requests,
+            request_seconds,
+            stage_seconds,
+            tokens,
+            errors,
+            cache_lookups,
+        })
+    }
+}
+
+// observes the stage duration when dropped
+pub fn stage(name: &str) -> HistogramTimer {
+    metrics()
+        .stage_seconds
+        .with_label_values(&[name])
+        .start_timer()
+}
+
+pub fn count_tokens(model: &str, prompt_tokens: usize, completion_tokens: usize) {
+    let tokens = &metrics().tokens;
+    tokens
+        .with_label_values(&[model, "prompt"])
+        .inc_by(prompt_tokens as u64);
+    tokens
+        .with_label_values(&[model, "completion"])
+        .inc_by(completion_tokens as u64);
+}
+
+pub fn count_error(code: &str) {
+    metrics().errors.with_label_values(&[code]).inc();
+}
+
+pub fn count_cache_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    metrics().cache_lookups.with_label_values(&[result]).inc();
+}
+
+// Middleware of the API routes, labelled by route pattern rather than path so
+// that ids don't make a series each.
+pub async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    let m = metrics();
+    m.requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    m.request_seconds
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+// the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer) {
+        tracing::warn!("metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
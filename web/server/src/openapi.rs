@@ -0,0 +1,115 @@
+use api_types::{
+    Answer, BlockReport, ChatKind, ChunkPage, ChunkType, ClientMessage, ContextReport,
+    DependencyCheck, Diversity, DocumentFormat, DocumentInfo, DocumentPage, DocumentRecord,
+    ErrorBody, Feedback, FeedbackRequest, GenerationParams, Job, JobStatus, KeyLimits, KeyUsage,
+    LogRecord, PackReason, PromptTemplate, QueryText, Rating, Readiness, RetrievedPoint,
+    SearchFilters, SearchMode, SectionInfo, SectionPage, ServerMessage, Session, Source,
+    TokenEvent, Turn, Usage,
+};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+// the two ways `authenticate` accepts a key
+struct ApiKeySchemes;
+
+impl Modify for ApiKeySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+        );
+    }
+}
+
+// The document served at `/api/openapi.json`, `just openapi` writes it to
+// `web/openapi.json` to generate clients from.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "llm_playground", description = "Search and question answering over the indexed documents"),
+    paths(
+        crate::post_query_for_similarity_search,
+        crate::post_query_for_answer_of_a_question,
+        crate::post_query_for_summary_of_a_topic,
+        crate::stream_query_for_answer_of_a_question,
+        crate::stream_query_for_summary_of_a_topic,
+        crate::get_documents,
+        crate::upload_document,
+        crate::get_sections,
+        crate::get_section_chunks,
+        crate::get_chunk,
+        crate::list_jobs,
+        crate::get_job,
+        crate::cancel_job,
+        crate::list_prompts,
+        crate::post_feedback,
+        crate::export_logs,
+        crate::create_session,
+        crate::get_session,
+        crate::delete_session,
+        crate::get_usage,
+        crate::clear_cache,
+        crate::chat_socket,
+        crate::readyz,
+    ),
+    components(schemas(
+        QueryText,
+        SearchMode,
+        Diversity,
+        SearchFilters,
+        ChunkType,
+        GenerationParams,
+        DocumentRecord,
+        Answer,
+        Source,
+        Usage,
+        ContextReport,
+        BlockReport,
+        PackReason,
+        TokenEvent,
+        ErrorBody,
+        Session,
+        Turn,
+        PromptTemplate,
+        FeedbackRequest,
+        Rating,
+        Feedback,
+        LogRecord,
+        RetrievedPoint,
+        DocumentPage,
+        SectionPage,
+        ChunkPage,
+        DocumentInfo,
+        SectionInfo,
+        Job,
+        JobStatus,
+        DocumentFormat,
+        KeyUsage,
+        KeyLimits,
+        ChatKind,
+        ClientMessage,
+        ServerMessage,
+        Readiness,
+        DependencyCheck,
+        crate::UploadForm,
+    )),
+    tags(
+        (name = "search", description = "similarity search over chunks and sections"),
+        (name = "answer", description = "answers and summaries grounded in the search hits"),
+        (name = "documents", description = "browsing and uploading documents"),
+        (name = "jobs", description = "ingestion of the uploaded documents"),
+        (name = "sessions", description = "conversations for follow-up questions"),
+        (name = "prompts", description = "prompt templates"),
+        (name = "logs", description = "request log and answer feedback"),
+        (name = "usage", description = "token and cost usage of the API keys"),
+        (name = "health", description = "probes of the server and of its dependencies"),
+    ),
+    modifiers(&ApiKeySchemes),
+    // only enforced when `auth.enabled` is set
+    security(("bearer" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
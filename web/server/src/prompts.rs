@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use api_types::PromptTemplate;
+
+pub struct PromptLibrary {
+    /// by id
+    templates: BTreeMap<String, PromptTemplate>,
+}
+
+impl PromptLibrary {
+    pub fn load(dir: &str) -> Result<Self> {
+        let mut templates = BTreeMap::new();
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("can't read the prompts directory {}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let template = read_template(&path)?;
+            if !template.has_placeholder(&template.input) {
+                bail!(
+                    "{} doesn't use its input placeholder {{{{{}}}}}",
+                    path.display(),
+                    template.input
+                );
+            }
+            if let Some(other) = templates.insert(template.id(), template) {
+                bail!("{} is defined twice in {}", other.id(), dir);
+            }
+        }
+        if templates.is_empty() {
+            bail!("no prompt template found in {}", dir);
+        }
+        Ok(Self { templates })
+    }
+
+    // an exact `name@vN` id, or a bare name for its latest version
+    pub fn get(&self, id: &str) -> Option<&PromptTemplate> {
+        if let Some(template) = self.templates.get(id) {
+            return Some(template);
+        }
+        self.templates
+            .values()
+            .filter(|t| t.name == id)
+            .max_by_key(|t| t.version)
+    }
+
+    pub fn list(&self) -> Vec<PromptTemplate> {
+        self.templates.values().cloned().collect()
+    }
+
+    // a configured template has to exist and fill the given placeholders
+    pub fn check(&self, id: &str, placeholders: &[&str]) -> Result<()> {
+        let Some(template) = self.get(id) else {
+            bail!("prompt template {} not found", id);
+        };
+        for placeholder in placeholders {
+            if !template.has_placeholder(placeholder) {
+                bail!("prompt template {} must contain {{{{{}}}}}", id, placeholder);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_template(path: &Path) -> Result<PromptTemplate> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("can't read the prompt template {}", path.display()))?;
+    toml::from_str(&text)
+        .with_context(|| format!("can't parse the prompt template {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> PromptLibrary {
+        PromptLibrary::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../prompts")).unwrap()
+    }
+
+    #[test]
+    fn bare_names_get_the_latest_version() {
+        let library = library();
+        assert_eq!(library.get("question_answering").unwrap().version, 2);
+        assert_eq!(library.get("question_answering@v1").unwrap().version, 1);
+        assert!(library.get("question_answering@v9").is_none());
+    }
+
+    #[test]
+    fn checks_the_placeholders_of_configured_templates() {
+        let library = library();
+        library
+            .check("condense_question@v1", &["history", "question"])
+            .unwrap();
+        let error = library
+            .check("question_answering@v1", &["history"])
+            .unwrap_err();
+        assert!(error.to_string().contains("must contain {{history}}"));
+        assert!(library.check("missing@v1", &[]).is_err());
+    }
+}
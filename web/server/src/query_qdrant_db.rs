@@ -1,320 +1,347 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::time::Duration;
 
-use anyhow::Result;
-use llm_chain::traits::Embeddings;
+use api_types::filters::{all_of, and, any_of, match_keyword, Level};
+use api_types::{Diversity, DocumentRecord, SearchFilters};
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::WithVectorsSelector;
 use qdrant_client::qdrant::{
-    condition::ConditionOneOf, r#match::MatchValue, with_payload_selector, with_vectors_selector,
-    Condition, FieldCondition, Filter, Match, ScrollPoints, WithPayloadSelector,
+    vectors::VectorsOptions, with_payload_selector, with_vectors_selector, Filter, PointId,
+    RetrievedPoint, ScrollPoints, Value, Vectors, WithPayloadSelector,
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct DocumentRecord {
-    pub score: Option<f32>,
-    pub file_name: Option<String>,
-    pub url: Option<String>,
-    pub document_id: Option<usize>,
-    pub section_id: Option<usize>,
-    pub chunk_id: Option<usize>,
-    pub keywords: Option<String>,
-    pub text: Option<String>,
-    pub vec: Option<Vec<f32>>,
-}
-
-pub async fn query_for_chunks(text: &str, topn: u64) -> Result<Vec<DocumentRecord>> {
-    let mut config = QdrantClientConfig::from_url("http://localhost:6334");
-    config.set_timeout(Duration::new(100, 0));
-    let client = QdrantClient::new(Some(config))?;
-    let collections_list = client.list_collections().await?;
-    dbg!(collections_list);
-    let collection_name = "NBK1116_chunks";
-
-    // Keep the keyword data in a database
-    let mut fn_to_keywords = HashMap::<String, Vec<String>>::default();
-    let keyword_file = BufReader::new(File::open("../test_doc/keywords.jsonl")?);
-    keyword_file.lines().for_each(|line| {
-        let (_doc_id, file_name, keywords): (usize, String, Vec<String>) =
-            serde_json::from_str(line.unwrap().as_str()).expect("failed json conversion");
-        fn_to_keywords.insert(file_name, keywords);
-    });
-
-    let query_str = text.to_owned();
-    println!("{}", query_str.clone());
-    let embeddings = llm_chain_openai::embeddings::Embeddings::default();
-    let embedded_vecs = embeddings
-        .embed_texts(vec![query_str.clone()])
-        .await
-        .unwrap();
+use crate::diversify::diversify;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::state::AppState;
 
-    //println!("{}", points[1120].text);
-    let search_result = client
-        .search_points(&SearchPoints {
-            collection_name: collection_name.into(),
-            vector: embedded_vecs[0].clone(),
-            filter: None,
-            limit: topn,
-            with_vectors: None,
-            with_payload: Some(WithPayloadSelector {
-                selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-            }),
-            params: None,
-            score_threshold: None,
-            offset: None,
-            ..Default::default()
-        })
-        .await?;
-    let mut return_docs = Vec::new();
+type Result<T> = std::result::Result<T, ApiError>;
 
-    search_result.result.into_iter().for_each(|p| {
-        let file_name = serde_json::to_string(p.payload.get("file_name").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .to_string();
+fn point_vector(vectors: Option<Vectors>) -> Option<Vec<f32>> {
+    match vectors?.vectors_options? {
+        VectorsOptions::Vector(v) => Some(v.data),
+        _ => None,
+    }
+}
 
-        let document_id = serde_json::to_string(p.payload.get("document_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
+fn with_vectors(enable: bool) -> Option<WithVectorsSelector> {
+    Some(WithVectorsSelector {
+        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(enable)),
+    })
+}
 
-        let section_id = serde_json::to_string(p.payload.get("section_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
+fn with_payload() -> Option<WithPayloadSelector> {
+    Some(WithPayloadSelector {
+        selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+    })
+}
 
-        let chunk_id = serde_json::to_string(p.payload.get("chunk_id").unwrap())
+pub fn payload_string(payload: &HashMap<String, Value>, key: &str) -> Option<String> {
+    payload.get(key).map(|v| {
+        serde_json::to_string(v)
             .expect("json conversion fails")
             .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
-
-        let text =
-            serde_json::to_string(p.payload.get("text").unwrap()).expect("json conversion fails");
-
-        let tmp = vec![];
-        let keywords = fn_to_keywords.get(&file_name).unwrap_or(&tmp);
-        let mut keywords = keywords.clone();
-        keywords.sort();
-        let prefix = file_name.as_str().split('.').next().unwrap();
-        let url = "https://www.ncbi.nlm.nih.gov/books/n/gene/".to_string() + prefix;
-        let return_doc = DocumentRecord {
-            score: Some(p.score),
-            file_name: Some(file_name),
-            url: Some(url),
-            document_id: Some(document_id),
-            section_id: Some(section_id),
-            chunk_id: Some(chunk_id),
-            keywords: Some(keywords.join("\n")),
-            text: Some(text),
-            ..Default::default()
-        };
-
-        return_docs.push(return_doc);
-    });
-
-    Ok(return_docs)
+            .to_string()
+    })
 }
 
-pub async fn query_for_sections(text: &str, topn: u64) -> Result<Vec<DocumentRecord>> {
-    let mut config = QdrantClientConfig::from_url("http://localhost:6334");
-    config.set_timeout(Duration::new(100, 0));
-    let client = QdrantClient::new(Some(config))?;
-    let collections_list = client.list_collections().await?;
-    dbg!(collections_list);
-    let collection_name = "NBK1116_sections";
-
-    // Keep the keyword data in a database
-    let mut fn_to_keywords = HashMap::<String, Vec<String>>::default();
-    let keyword_file = BufReader::new(File::open("../test_doc/keywords.jsonl")?);
-    keyword_file.lines().for_each(|line| {
-        let (_doc_id, file_name, keywords): (usize, String, Vec<String>) =
-            serde_json::from_str(line.unwrap().as_str()).expect("failed json conversion");
-        fn_to_keywords.insert(file_name, keywords);
-    });
+pub fn payload_usize(payload: &HashMap<String, Value>, key: &str) -> Option<usize> {
+    payload_string(payload, key).map(|v| v.parse::<usize>().expect("number parsing error"))
+}
 
-    let query_str = text.to_owned();
-    println!("{}", query_str.clone());
-    let embeddings = llm_chain_openai::embeddings::Embeddings::default();
-    let embedded_vecs = embeddings
-        .embed_texts(vec![query_str.clone()])
-        .await?;
+pub fn record_from_payload(state: &AppState, payload: &HashMap<String, Value>) -> DocumentRecord {
+    let file_name = payload_string(payload, "file_name").unwrap_or_default();
+    DocumentRecord {
+        url: Some(state.document_url(&file_name)),
+        document_id: payload_usize(payload, "document_id"),
+        section_id: payload_usize(payload, "section_id"),
+        chunk_id: payload_usize(payload, "chunk_id"),
+        keywords: Some(state.keywords(&file_name).join("\n")),
+        text: payload
+            .get("text")
+            .map(|t| serde_json::to_string(t).expect("json conversion fails")),
+        file_name: Some(file_name),
+        ..Default::default()
+    }
+}
 
-    //println!("{}", points[1120].text);
-    let search_result = client
-        .search_points(&SearchPoints {
-            collection_name: collection_name.into(),
-            vector: embedded_vecs[0].clone(),
-            filter: None,
-            limit: topn,
-            with_vectors: None,
-            with_payload: Some(WithPayloadSelector {
-                selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-            }),
-            params: None,
-            score_threshold: None,
-            offset: None,
-            ..Default::default()
+async fn search_records(
+    state: &AppState,
+    level: Level,
+    vector: Vec<f32>,
+    filter: Option<Filter>,
+    limit: u64,
+    vectors: bool,
+) -> Result<Vec<DocumentRecord>> {
+    let _timer = metrics::stage("search");
+    let search_result = state
+        .limits
+        .search
+        .run(async {
+            state
+                .client
+                .search_points(&SearchPoints {
+                    collection_name: state.collection(level),
+                    vector,
+                    filter,
+                    limit,
+                    with_vectors: with_vectors(vectors),
+                    with_payload: with_payload(),
+                    params: None,
+                    score_threshold: None,
+                    offset: None,
+                    ..Default::default()
+                })
+                .await
+                .map_err(ApiError::vector_store)
         })
         .await?;
-    let mut return_docs = Vec::new();
 
-    for p in search_result.result.into_iter() {
-        let file_name = serde_json::to_string(p.payload.get("file_name").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .to_string();
+    Ok(search_result
+        .result
+        .into_iter()
+        .map(|p| DocumentRecord {
+            score: Some(p.score),
+            vec: point_vector(p.vectors),
+            ..record_from_payload(state, &p.payload)
+        })
+        .collect())
+}
 
-        let document_id = serde_json::to_string(p.payload.get("document_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
+// every point of `level` matching `filter`, scrolled a page at a time; the
+// whole scroll is one call of the search stage
+pub async fn scroll_all(
+    state: &AppState,
+    level: Level,
+    filter: Option<Filter>,
+    vectors: bool,
+) -> Result<Vec<RetrievedPoint>> {
+    state
+        .limits
+        .search
+        .run(async {
+            let mut points = Vec::new();
+            let mut offset: Option<PointId> = None;
+            loop {
+                let scroll_points = ScrollPoints {
+                    collection_name: state.collection(level),
+                    filter: filter.clone(),
+                    offset: offset.clone(),
+                    limit: Some(256),
+                    with_payload: with_payload(),
+                    with_vectors: with_vectors(vectors),
+                    ..Default::default()
+                };
+                let search_result = state
+                    .client
+                    .scroll(&scroll_points)
+                    .await
+                    .map_err(ApiError::vector_store)?;
+                points.extend(search_result.result);
+                offset = search_result.next_page_offset;
+                if offset.is_none() {
+                    break;
+                }
+            }
+            Ok(points)
+        })
+        .await
+}
 
-        let section_id = serde_json::to_string(p.payload.get("section_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
+// the chunks matching `filter` in document, section and chunk order
+async fn scroll_chunks(state: &AppState, filter: Filter) -> Result<Vec<DocumentRecord>> {
+    let mut return_docs = scroll_all(state, Level::Chunks, Some(filter), true)
+        .await?
+        .into_iter()
+        .map(|p| DocumentRecord {
+            vec: point_vector(p.vectors),
+            ..record_from_payload(state, &p.payload)
+        })
+        .collect::<Vec<_>>();
+    return_docs.sort_by_key(|r| (r.document_id, r.section_id, r.chunk_id));
+    Ok(return_docs)
+}
 
-        let records = fetch_doc_sec(document_id as u64, section_id as u64);
-        let records = records.await;
-        let text = if let Ok(records) = records {
-            records.into_iter().map(|f| {
-                f.text.unwrap_or("".to_string())
-            }).collect::<Vec<_>>().join("\n")
-        } else {
-            "".to_string()
-        };
+pub async fn query_for_chunks(
+    state: &AppState,
+    query_vec: Vec<f32>,
+    topn: u64,
+    diversity: &Diversity,
+    filters: &SearchFilters,
+) -> Result<Vec<DocumentRecord>> {
+    let mut return_docs = search_records(
+        state,
+        Level::Chunks,
+        query_vec.clone(),
+        filters.to_qdrant_filter(&state.fn_to_keywords, Level::Chunks),
+        diversity.candidate_limit(topn),
+        diversity.is_enabled(),
+    )
+    .await?;
+
+    if diversity.is_enabled() {
+        return_docs = diversify(&query_vec, return_docs, topn as usize, diversity);
+        return_docs.iter_mut().for_each(|r| r.vec = None);
+    }
 
-        let tmp = vec![];
-        let keywords = fn_to_keywords.get(&file_name).unwrap_or(&tmp);
-        let mut keywords = keywords.clone();
-        keywords.sort();
-        let prefix = file_name.as_str().split('.').next().unwrap();
-        let url = "https://www.ncbi.nlm.nih.gov/books/n/gene/".to_string() + prefix;
-        let return_doc = DocumentRecord {
-            score: Some(p.score),
-            file_name: Some(file_name),
-            url: Some(url),
-            document_id: Some(document_id),
-            section_id: Some(section_id),
-            chunk_id: None,
-            keywords: Some(keywords.join("\n")),
-            text: Some(text),
-            ..Default::default()
-        };
+    Ok(return_docs)
+}
 
-        return_docs.push(return_doc);
-    };
+pub async fn query_for_sections(
+    state: &AppState,
+    query_vec: Vec<f32>,
+    topn: u64,
+    diversity: &Diversity,
+    filters: &SearchFilters,
+) -> Result<Vec<DocumentRecord>> {
+    let mut return_docs = search_records(
+        state,
+        Level::Sections,
+        query_vec.clone(),
+        filters.to_qdrant_filter(&state.fn_to_keywords, Level::Sections),
+        diversity.candidate_limit(topn),
+        diversity.is_enabled(),
+    )
+    .await?;
+
+    // diversify on the section vectors before fetching the section text
+    if diversity.is_enabled() {
+        return_docs = diversify(&query_vec, return_docs, topn as usize, diversity);
+    }
+
+    let sections = return_docs
+        .iter()
+        .filter_map(|r| Some((r.document_id? as u64, r.section_id? as u64)))
+        .collect::<Vec<_>>();
+    let mut sec_to_text = HashMap::<(usize, usize), Vec<String>>::new();
+    for chunk in fetch_sections(state, &sections).await? {
+        if let (Some(doc_id), Some(sec_id)) = (chunk.document_id, chunk.section_id) {
+            sec_to_text
+                .entry((doc_id, sec_id))
+                .or_default()
+                .push(chunk.text.unwrap_or_default());
+        }
+    }
+
+    return_docs.iter_mut().for_each(|r| {
+        let key = (r.document_id.unwrap_or_default(), r.section_id.unwrap_or_default());
+        r.text = Some(sec_to_text.get(&key).map(|t| t.join("\n")).unwrap_or_default());
+        r.chunk_id = None;
+        r.vec = None;
+    });
 
     Ok(return_docs)
 }
 
-pub async fn fetch_doc_sec(doc_id: u64, sec_id: u64) -> Result<Vec<DocumentRecord>> {
-    let mut config = QdrantClientConfig::from_url("http://localhost:6334");
-    config.set_timeout(Duration::new(100, 0));
-    let client = QdrantClient::new(Some(config))?;
-    let collection_name = "NBK1116_chunks";
-
-    // Keep the keyword data in a database
-    let mut fn_to_keywords = HashMap::<String, Vec<String>>::default();
-    let keyword_file = BufReader::new(File::open("../test_doc/keywords.jsonl")?);
-    keyword_file.lines().for_each(|line| {
-        let (_doc_id, file_name, keywords): (usize, String, Vec<String>) =
-            serde_json::from_str(line.unwrap().as_str()).expect("failed json conversion");
-        fn_to_keywords.insert(file_name, keywords);
-    });
+pub async fn fetch_doc_sec(
+    state: &AppState,
+    doc_id: u64,
+    sec_id: u64,
+) -> Result<Vec<DocumentRecord>> {
+    fetch_sections(state, &[(doc_id, sec_id)]).await
+}
 
+// the ordered chunks of several sections with a single scroll
+pub async fn fetch_sections(
+    state: &AppState,
+    sections: &[(u64, u64)],
+) -> Result<Vec<DocumentRecord>> {
+    if sections.is_empty() {
+        return Ok(vec![]);
+    }
     let filter = Filter {
-        must: vec![
-            Condition {
-                condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
-                    key: "document_id".to_string(),
-                    r#match: Some(Match {
-                        match_value: Some(MatchValue::Text(doc_id.to_string())),
-                    }),
-                    ..Default::default()
-                })),
-            },
-            Condition {
-                condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
-                    key: "section_id".to_string(),
-                    r#match: Some(Match {
-                        match_value: Some(MatchValue::Text(sec_id.to_string())),
-                    }),
-                    ..Default::default()
-                })),
-            },
-        ],
+        should: sections
+            .iter()
+            .map(|(doc_id, sec_id)| {
+                all_of(vec![
+                    match_keyword("document_id", doc_id.to_string()),
+                    match_keyword("section_id", sec_id.to_string()),
+                ])
+            })
+            .collect(),
         ..Default::default()
     };
+    scroll_chunks(state, filter).await
+}
 
-    let scroll_points = ScrollPoints {
-        collection_name: collection_name.to_string(),
-        filter: Some(filter),
-        with_vectors: Some(WithVectorsSelector {
-            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-        }),
-        ..Default::default()
-    };
-
-    //println!("{}", points[1120].text);
-    let search_result = client.scroll(&scroll_points).await?;
-    let mut return_docs = Vec::new();
-
-    search_result.result.into_iter().for_each(|p| {
-        let file_name = serde_json::to_string(p.payload.get("file_name").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .to_string();
-
-        let document_id = serde_json::to_string(p.payload.get("document_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
-
-        let section_id = serde_json::to_string(p.payload.get("section_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
-
-        let chunk_id = serde_json::to_string(p.payload.get("chunk_id").unwrap())
-            .expect("json conversion fails")
-            .trim_matches('"')
-            .parse::<usize>()
-            .expect("number parsing error");
-
-        let text =
-            serde_json::to_string(p.payload.get("text").unwrap()).expect("json conversion fails");
-
-        let tmp = vec![];
-        let keywords = fn_to_keywords.get(&file_name).unwrap_or(&tmp);
-        let mut keywords = keywords.clone();
-        keywords.sort();
-        let prefix = file_name.as_str().split('.').next().unwrap();
-        let url = "https://www.ncbi.nlm.nih.gov/books/n/gene/".to_string() + prefix;
-        let return_doc = DocumentRecord {
-            score: None,
-            file_name: Some(file_name),
-            url: Some(url),
-            document_id: Some(document_id),
-            section_id: Some(section_id),
-            chunk_id: Some(chunk_id),
-            keywords: Some(keywords.join("\n")),
-            text: Some(text),
-            ..Default::default()
-        };
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct HierarchicalTopK {
+    pub documents: u64,
+    pub sections: u64,
+    pub chunks: u64,
+}
 
-        return_docs.push(return_doc);
-    });
+// Coarse-to-fine retrieval: search the mean pooled document vectors first,
+// then the sections of the top documents, then the chunks of the top sections.
+pub async fn query_hierarchical(
+    state: &AppState,
+    query_vec: Vec<f32>,
+    topk: HierarchicalTopK,
+    diversity: &Diversity,
+    filters: &SearchFilters,
+) -> Result<Vec<DocumentRecord>> {
+    let fn_to_keywords = &state.fn_to_keywords;
+
+    let documents = search_records(
+        state,
+        Level::Documents,
+        query_vec.clone(),
+        filters.to_qdrant_filter(fn_to_keywords, Level::Documents),
+        topk.documents,
+        false,
+    )
+    .await?;
+    let document_conditions = documents
+        .iter()
+        .filter_map(|r| r.document_id)
+        .map(|doc_id| match_keyword("document_id", doc_id.to_string()))
+        .collect::<Vec<_>>();
+    if document_conditions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let sections = search_records(
+        state,
+        Level::Sections,
+        query_vec.clone(),
+        and(
+            filters.to_qdrant_filter(fn_to_keywords, Level::Sections),
+            vec![any_of(document_conditions)],
+        ),
+        topk.sections,
+        false,
+    )
+    .await?;
+    let section_conditions = sections
+        .iter()
+        .filter_map(|r| {
+            Some(all_of(vec![
+                match_keyword("document_id", r.document_id?.to_string()),
+                match_keyword("section_id", r.section_id?.to_string()),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    if section_conditions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut return_docs = search_records(
+        state,
+        Level::Chunks,
+        query_vec.clone(),
+        and(
+            filters.to_qdrant_filter(fn_to_keywords, Level::Chunks),
+            vec![any_of(section_conditions)],
+        ),
+        diversity.candidate_limit(topk.chunks),
+        diversity.is_enabled(),
+    )
+    .await?;
+
+    if diversity.is_enabled() {
+        return_docs = diversify(&query_vec, return_docs, topk.chunks as usize, diversity);
+        return_docs.iter_mut().for_each(|r| r.vec = None);
+    }
 
     Ok(return_docs)
 }
@@ -0,0 +1,188 @@
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow::{bail, Error as E, Result};
+use api_types::DocumentRecord;
+use candle_core::{Device, IndexOp, Module, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use tokenizers::{Tokenizer, TruncationParams};
+
+use crate::limits::CancelOnDrop;
+use crate::metrics;
+
+static CROSS_ENCODER: OnceLock<CrossEncoder> = OnceLock::new();
+
+// A BERT style cross-encoder (`BertForSequenceClassification` with a single
+// logit) that scores a (query, passage) pair jointly.
+pub struct CrossEncoder {
+    bert: BertModel,
+    pooler: Linear,
+    classifier: Linear,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl CrossEncoder {
+    pub fn load(model_id: &str) -> Result<Self> {
+        let device = Device::Cpu;
+        let api = Api::new()?;
+        let repo = api.repo(Repo::with_revision(
+            model_id.to_string(),
+            RepoType::Model,
+            "main".to_string(),
+        ));
+        let config_filename = repo.get("config.json")?;
+        let tokenizer_filename = repo.get("tokenizer.json")?;
+        let weights_filename = repo.get("model.safetensors")?;
+
+        let config = std::fs::read_to_string(config_filename)?;
+        let hidden_size = serde_json::from_str::<serde_json::Value>(&config)?["hidden_size"]
+            .as_u64()
+            .ok_or_else(|| E::msg("hidden_size missing in the reranker config"))?
+            as usize;
+        let config: Config = serde_json::from_str(&config)?;
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        tokenizer.with_truncation(Some(TruncationParams {
+            max_length: 512,
+            ..Default::default()
+        }));
+
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? };
+        let bert = BertModel::load(vb.clone(), &config)?;
+        let pooler = candle_nn::linear(hidden_size, hidden_size, vb.pp("bert.pooler.dense"))?;
+        let classifier = candle_nn::linear(hidden_size, 1, vb.pp("classifier"))?;
+
+        Ok(Self {
+            bert,
+            pooler,
+            classifier,
+            tokenizer,
+            device,
+        })
+    }
+
+    pub fn score(&self, query: &str, passage: &str) -> Result<f32> {
+        let encoding = self
+            .tokenizer
+            .encode((query, passage), true)
+            .map_err(E::msg)?;
+        let input_ids = Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)?;
+        let token_type_ids = Tensor::new(encoding.get_type_ids(), &self.device)?.unsqueeze(0)?;
+        let hidden = self.bert.forward(&input_ids, &token_type_ids)?;
+        let cls = hidden.i((.., 0))?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logits = self.classifier.forward(&pooled)?;
+        let score = logits.squeeze(1)?.squeeze(0)?.to_scalar::<f32>()?;
+        Ok(score)
+    }
+}
+
+// loaded on the first rerank request, `model_id` comes from the server config
+pub fn cross_encoder(model_id: &str) -> Result<&'static CrossEncoder> {
+    if let Some(model) = CROSS_ENCODER.get() {
+        return Ok(model);
+    }
+    let model = CrossEncoder::load(model_id)?;
+    let _ = CROSS_ENCODER.set(model);
+    Ok(CROSS_ENCODER.get().expect("reranker is initialized"))
+}
+
+// Rescore the first stage candidates with the cross-encoder and keep the
+// `topn` best ones. The first stage cosine score is kept in `score`. Scoring
+// stops between passages once the future is dropped.
+pub async fn rerank(
+    model_id: &str,
+    query: &str,
+    records: Vec<DocumentRecord>,
+    topn: u64,
+) -> Result<Vec<DocumentRecord>> {
+    let _timer = metrics::stage("rerank");
+    let model_id = model_id.to_owned();
+    let query = query.to_owned();
+    let guard = CancelOnDrop::default();
+    let cancelled = guard.flag();
+    let mut records = tokio::task::spawn_blocking(move || -> Result<Vec<DocumentRecord>> {
+        let model = cross_encoder(&model_id)?;
+        records
+            .into_iter()
+            .map(|mut record| {
+                if cancelled.load(Ordering::Relaxed) {
+                    bail!("reranking cancelled");
+                }
+                let start = Instant::now();
+                let text = record.text.clone().unwrap_or_default();
+                record.rerank_score = Some(model.score(&query, &text)?);
+                record.rerank_latency_ms = Some(start.elapsed().as_millis() as u64);
+                Ok(record)
+            })
+            .collect()
+    })
+    .await??;
+    drop(guard);
+
+    Ok(best_reranked(records, topn as usize))
+}
+
+// the `topn` records of highest `rerank_score`, unscored ones last
+fn best_reranked(mut records: Vec<DocumentRecord>, topn: usize) -> Vec<DocumentRecord> {
+    records.sort_by(|a, b| {
+        b.rerank_score
+            .unwrap_or(f32::MIN)
+            .total_cmp(&a.rerank_score.unwrap_or(f32::MIN))
+    });
+    records.truncate(topn);
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(chunk_id: usize, score: f32, rerank_score: Option<f32>) -> DocumentRecord {
+        DocumentRecord {
+            chunk_id: Some(chunk_id),
+            score: Some(score),
+            rerank_score,
+            ..Default::default()
+        }
+    }
+
+    fn chunk_ids(records: &[DocumentRecord]) -> Vec<usize> {
+        records.iter().filter_map(|r| r.chunk_id).collect()
+    }
+
+    #[test]
+    fn keeps_the_topn_in_rerank_order() {
+        let records = vec![
+            record(0, 0.9, Some(-2.5)),
+            record(1, 0.8, Some(4.0)),
+            record(2, 0.7, Some(0.5)),
+            record(3, 0.6, Some(7.25)),
+        ];
+        let best = best_reranked(records.clone(), 3);
+        assert_eq!(chunk_ids(&best), vec![3, 1, 2]);
+        // the first stage score stays with its record
+        assert_eq!(best[0].score, Some(0.6));
+        assert_eq!(
+            chunk_ids(&best_reranked(records.clone(), 10)),
+            vec![3, 1, 2, 0]
+        );
+        assert!(best_reranked(records, 0).is_empty());
+    }
+
+    #[test]
+    fn unscored_records_come_last_in_their_order() {
+        let records = vec![
+            record(0, 0.9, None),
+            record(1, 0.8, Some(-100.0)),
+            record(2, 0.7, None),
+            record(3, 0.6, Some(1.0)),
+        ];
+        assert_eq!(chunk_ids(&best_reranked(records, 4)), vec![3, 1, 0, 2]);
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use api_types::{Session, Turn};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::error::ApiError;
+
+// Conversation history kept in a SQLite file so that sessions survive restarts.
+pub struct SessionStore {
+    pool: SqlitePool,
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl SessionStore {
+    pub async fn open(path: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                session_id TEXT PRIMARY KEY,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        // Turns are ordered by an id of their own, numbering them per session
+        // raced between concurrent answers in the same session. Files written
+        // with the numbered turns are moved over in one transaction.
+        let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info('turns')")
+            .fetch_all(&pool)
+            .await?;
+        let numbered = columns.iter().any(|(name,)| name == "turn");
+        let mut tx = pool.begin().await?;
+        if numbered {
+            sqlx::query("ALTER TABLE turns RENAME TO numbered_turns")
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS turns (
+                turn_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id TEXT NOT NULL REFERENCES sessions(session_id) ON DELETE CASCADE,
+                question TEXT NOT NULL,
+                standalone_query TEXT NOT NULL,
+                answer TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&mut *tx)
+        .await?;
+        if numbered {
+            sqlx::query(
+                "INSERT INTO turns (session_id, question, standalone_query, answer, created_at)
+                 SELECT session_id, question, standalone_query, answer, created_at
+                 FROM numbered_turns ORDER BY session_id, turn",
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("DROP TABLE numbered_turns")
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("CREATE INDEX IF NOT EXISTS turns_by_session ON turns (session_id, turn_id)")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Self { pool })
+    }
+
+    pub async fn create(&self) -> Result<Session, ApiError> {
+        let created_at = now_secs();
+        let (session_id,): (String,) = sqlx::query_as(
+            "INSERT INTO sessions (session_id, created_at)
+             VALUES (lower(hex(randomblob(16))), ?)
+             RETURNING session_id",
+        )
+        .bind(created_at)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        Ok(Session {
+            session_id,
+            created_at,
+            turns: vec![],
+        })
+    }
+
+    pub async fn get(&self, session_id: &str) -> Result<Session, ApiError> {
+        let created_at: Option<(i64,)> =
+            sqlx::query_as("SELECT created_at FROM sessions WHERE session_id = ?")
+                .bind(session_id)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(ApiError::internal)?;
+        let Some((created_at,)) = created_at else {
+            return Err(ApiError::NotFound(format!("session {} not found", session_id)));
+        };
+        let turns = sqlx::query_as::<_, Turn>(
+            "SELECT question, standalone_query, answer, created_at FROM turns
+             WHERE session_id = ? ORDER BY turn_id",
+        )
+        .bind(session_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        Ok(Session {
+            session_id: session_id.to_string(),
+            created_at,
+            turns,
+        })
+    }
+
+    // the last `limit` turns, oldest first
+    pub async fn history(&self, session_id: &str, limit: usize) -> Result<Vec<Turn>, ApiError> {
+        let mut turns = self.get(session_id).await?.turns;
+        let skip = turns.len().saturating_sub(limit);
+        Ok(turns.split_off(skip))
+    }
+
+    pub async fn add_turn(
+        &self,
+        session_id: &str,
+        question: &str,
+        standalone_query: &str,
+        answer: &str,
+    ) -> Result<(), ApiError> {
+        sqlx::query(
+            "INSERT INTO turns (session_id, question, standalone_query, answer, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(session_id)
+        .bind(question)
+        .bind(standalone_query)
+        .bind(answer)
+        .bind(now_secs())
+        .execute(&self.pool)
+        .await
+        .map_err(ApiError::internal)?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, session_id: &str) -> Result<(), ApiError> {
+        sqlx::query("DELETE FROM turns WHERE session_id = ?")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await
+            .map_err(ApiError::internal)?;
+        let deleted = sqlx::query("DELETE FROM sessions WHERE session_id = ?")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await
+            .map_err(ApiError::internal)?;
+        if deleted.rows_affected() == 0 {
+            return Err(ApiError::NotFound(format!("session {} not found", session_id)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use api_types::filters::Level;
+use qdrant_client::prelude::*;
+
+use crate::audit::AuditLog;
+use crate::auth::Auth;
+use crate::cache::AnswerCache;
+use crate::config::ServerConfig;
+use crate::embeddings::OpenAiEmbeddings;
+use crate::error::ApiError;
+use crate::jobs::JobQueue;
+use crate::limits::Limits;
+use crate::llm::LlmRegistry;
+use crate::metrics;
+use crate::prompts::PromptLibrary;
+use crate::sessions::SessionStore;
+use crate::tokens::count_tokens;
+
+const QUERY_VECTORS: usize = 256;
+
+// Everything the handlers need that is expensive to build, created once at
+// startup and shared through axum's `State`.
+pub struct AppState {
+    pub config: ServerConfig,
+    pub client: QdrantClient,
+    pub fn_to_keywords: HashMap<String, Vec<String>>,
+    pub embeddings: OpenAiEmbeddings,
+    pub sessions: SessionStore,
+    pub audit: AuditLog,
+    pub auth: Auth,
+    pub llms: LlmRegistry,
+    pub prompts: PromptLibrary,
+    pub jobs: JobQueue,
+    pub cache: AnswerCache,
+    pub limits: Limits,
+    // the vectors of the last queries, the answer cache and the search embed
+    // the same text
+    query_vectors: Mutex<HashMap<String, Vec<f32>>>,
+}
+
+pub type SharedState = Arc<AppState>;
+
+fn load_keywords(path: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut fn_to_keywords = HashMap::<String, Vec<String>>::default();
+    let keyword_file = BufReader::new(File::open(path)?);
+    for line in keyword_file.lines() {
+        let (_doc_id, file_name, keywords): (usize, String, Vec<String>) =
+            serde_json::from_str(line?.as_str())?;
+        fn_to_keywords.insert(file_name, keywords);
+    }
+    Ok(fn_to_keywords)
+}
+
+impl AppState {
+    pub async fn new(config: ServerConfig) -> Result<Self> {
+        let mut qdrant_config = QdrantClientConfig::from_url(&config.qdrant_url);
+        qdrant_config.set_timeout(Duration::new(config.qdrant_timeout_secs, 0));
+        let client = QdrantClient::new(Some(qdrant_config))?;
+        let collections = client.list_collections().await?;
+        tracing::info!(
+            "qdrant collections: {:?}",
+            collections
+                .collections
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let fn_to_keywords = load_keywords(&config.keywords_path)?;
+        let embeddings = OpenAiEmbeddings::new(&config.embedding, &config.openai_base_url);
+        let sessions = SessionStore::open(&config.session_db).await?;
+        let audit = AuditLog::open(&config.audit_db).await?;
+        let auth = Auth::open(&config.auth).await?;
+        let llms = LlmRegistry::new(&config);
+        let cache = AnswerCache::new(&config.cache);
+        let limits = Limits::new(&config.limits);
+        let jobs = JobQueue::new(config.max_queued_jobs);
+        let prompts = PromptLibrary::load(&config.prompts_dir)?;
+        prompts.check(&config.question_prompt, &["context"])?;
+        prompts.check(&config.summary_prompt, &["context"])?;
+        prompts.check(&config.condense_prompt, &["history"])?;
+
+        Ok(Self {
+            config,
+            client,
+            fn_to_keywords,
+            embeddings,
+            sessions,
+            audit,
+            auth,
+            llms,
+            prompts,
+            jobs,
+            cache,
+            limits,
+            query_vectors: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn collection(&self, level: Level) -> String {
+        let suffix = match level {
+            Level::Documents => "documents",
+            Level::Sections => "sections",
+            Level::Chunks => "chunks",
+        };
+        format!("{}_{}", self.config.collection_prefix, suffix)
+    }
+
+    // the query vector of `text`, embedding it is counted against `api_key`
+    pub async fn embed(&self, text: &str, api_key: Option<&str>) -> Result<Vec<f32>, ApiError> {
+        if let Some(vector) = self.query_vectors.lock().expect("vectors lock").get(text) {
+            return Ok(vector.clone());
+        }
+        let _timer = metrics::stage("embed");
+        let mut embedded_vecs = self
+            .limits
+            .embed
+            .run(async {
+                self.embeddings
+                    .embed_texts(vec![text.to_owned()])
+                    .await
+                    .map_err(|e| ApiError::upstream(format!("embedding: {}", e)))
+            })
+            .await?;
+        if embedded_vecs.is_empty() {
+            return Err(ApiError::Upstream("embedding: no vector returned".to_string()));
+        }
+        let vector = embedded_vecs.swap_remove(0);
+        let model = &self.config.embedding.model;
+        self.audit
+            .spend(api_key, "embedding", model, count_tokens(model, text), 0)
+            .await;
+        let mut vectors = self.query_vectors.lock().expect("vectors lock");
+        if vectors.len() >= QUERY_VECTORS {
+            vectors.clear();
+        }
+        vectors.insert(text.to_owned(), vector.clone());
+        Ok(vector)
+    }
+
+    pub fn keywords(&self, file_name: &str) -> Vec<String> {
+        let mut keywords = self
+            .fn_to_keywords
+            .get(file_name)
+            .cloned()
+            .unwrap_or_default();
+        keywords.sort();
+        keywords
+    }
+
+    pub fn document_url(&self, file_name: &str) -> String {
+        let prefix = file_name.split('.').next().unwrap_or_default();
+        self.config.document_url_template.replace("{prefix}", prefix)
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tiktoken_rs::CoreBPE;
+
+static BPE_BY_MODEL: OnceLock<Mutex<HashMap<String, Arc<CoreBPE>>>> = OnceLock::new();
+
+// The tokenizer of an OpenAI model, building a BPE is slow so they are kept
+// around. Unknown models fall back to cl100k_base.
+pub fn bpe_for_model(model: &str) -> Arc<CoreBPE> {
+    let cache = BPE_BY_MODEL.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut cache = cache.lock().expect("tokenizer cache lock");
+    cache
+        .entry(model.to_string())
+        .or_insert_with(|| {
+            let bpe = tiktoken_rs::get_bpe_from_model(model)
+                .or_else(|_| tiktoken_rs::cl100k_base())
+                .expect("the cl100k_base tokenizer is bundled");
+            Arc::new(bpe)
+        })
+        .clone()
+}
+
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    bpe_for_model(model).encode_with_special_tokens(text).len()
+}
+
+// The longest prefix of `text` that fits in `max_tokens`. A cut inside a
+// multi-byte character doesn't decode, so back off a token at a time.
+pub fn truncate_to_tokens(model: &str, text: &str, max_tokens: usize) -> String {
+    let bpe = bpe_for_model(model);
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+    let mut end = max_tokens;
+    while end > 0 {
+        if let Ok(prefix) = bpe.decode(tokens[..end].to_vec()) {
+            return prefix;
+        }
+        end -= 1;
+    }
+    String::new()
+}
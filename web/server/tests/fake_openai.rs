@@ -0,0 +1,235 @@
+// The fake OpenAI server in the test process, and the server binary against
+// it, both on free local ports. The server lists the Qdrant collections when
+// it starts, its test only runs when `LLM_PLAYGROUND_QDRANT_URL` points to a
+// Qdrant with the collections.
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use api_types::{Answer, Readiness};
+use serde_json::{json, Value};
+
+#[path = "../src/bin/fake_openai/app.rs"]
+mod fake_openai;
+
+const DIM: usize = 1536;
+const REPLY: &str = "NARS2 is a mitochondrial aminoacyl-tRNA synthetase [1].";
+
+// an address on which nothing listens yet
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("a free port")
+}
+
+// the `/v1` root of a fake started on a runtime of its own, it outlives the
+// runtime of each test
+fn start_fake() -> String {
+    let addr = free_addr();
+    let listener = TcpListener::bind(addr).expect("the fake's port");
+    listener
+        .set_nonblocking(true)
+        .expect("non-blocking listener");
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().expect("the fake's runtime");
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).expect("tokio listener");
+            let app = fake_openai::router(fake_openai::Replies {
+                dim: DIM,
+                reply: REPLY.to_string(),
+            });
+            axum::serve(listener, app).await.expect("the fake server");
+        })
+    });
+    format!("http://{}/v1", addr)
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+async fn embed(client: &reqwest::Client, base_url: &str, input: Value) -> Vec<Vec<f32>> {
+    let response: Value = client
+        .post(format!("{}/embeddings", base_url))
+        .json(&json!({"model": "text-embedding-ada-002", "input": input}))
+        .send()
+        .await
+        .expect("embedding request")
+        .json()
+        .await
+        .expect("embedding response");
+    response["data"]
+        .as_array()
+        .expect("embedding data")
+        .iter()
+        .map(|d| serde_json::from_value(d["embedding"].clone()).expect("embedding vector"))
+        .collect()
+}
+
+#[tokio::test]
+async fn fake_answers_embeddings_and_completions() {
+    let base_url = start_fake();
+    let client = reqwest::Client::new();
+
+    let vectors = embed(
+        &client,
+        &base_url,
+        json!(["the NARS2 gene", "NARS2 gene", "hearing loss"]),
+    )
+    .await;
+    assert_eq!(vectors.len(), 3);
+    assert!(vectors.iter().all(|v| v.len() == DIM));
+    assert!((cosine(&vectors[0], &vectors[0]) - 1.).abs() < 1e-5);
+    assert!(cosine(&vectors[0], &vectors[1]) > cosine(&vectors[0], &vectors[2]));
+    // a single input is answered like a list of one, always the same vector
+    let single = embed(&client, &base_url, json!("NARS2 gene")).await;
+    assert_eq!(single, vec![vectors[1].clone()]);
+
+    let chat = json!({
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "What is NARS2?"}],
+    });
+    let completion: Value = client
+        .post(format!("{}/chat/completions", base_url))
+        .json(&chat)
+        .send()
+        .await
+        .expect("completion request")
+        .json()
+        .await
+        .expect("completion response");
+    assert_eq!(completion["choices"][0]["message"]["content"], REPLY);
+
+    let mut streamed = chat;
+    streamed["stream"] = json!(true);
+    let events = client
+        .post(format!("{}/chat/completions", base_url))
+        .json(&streamed)
+        .send()
+        .await
+        .expect("streaming request")
+        .text()
+        .await
+        .expect("streamed events");
+    let text = events
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter(|data| *data != "[DONE]")
+        .map(|data| {
+            let chunk: Value = serde_json::from_str(data).expect("chunk json");
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect::<String>();
+    assert_eq!(text, REPLY);
+    assert!(events.contains("data: [DONE]"));
+}
+
+// the server process, killed when the test ends
+struct Server {
+    child: Child,
+    url: String,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// a config in a directory of its own, for the SQLite files
+fn write_config(dir: &Path, base_url: &str) -> PathBuf {
+    std::fs::create_dir_all(dir).expect("the server's directory");
+    std::fs::write(dir.join("keywords.jsonl"), "").expect("keywords file");
+    let prompts = Path::new(env!("CARGO_MANIFEST_DIR")).join("../prompts");
+    let config = format!(
+        "openai_base_url = {:?}\n\
+         keywords_path = \"keywords.jsonl\"\n\
+         prompts_dir = {:?}\n\
+         [cache]\n\
+         index_check_secs = 0\n",
+        base_url,
+        prompts.to_string_lossy()
+    );
+    let path = dir.join("server.toml");
+    std::fs::write(&path, config).expect("config file");
+    path
+}
+
+async fn start_server(base_url: &str) -> Server {
+    let dir = std::env::temp_dir().join(format!("fake_openai_test_{}", std::process::id()));
+    let config = write_config(&dir, base_url);
+    let addr = free_addr();
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--addr", "127.0.0.1", "--port", &addr.port().to_string()])
+        .arg("--config")
+        .arg(&config)
+        .env("OPENAI_API_KEY", "fake")
+        .env_remove("LLM_PLAYGROUND_OPENAI_BASE_URL")
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("the server binary");
+    let server = Server {
+        child,
+        url: format!("http://{}", addr),
+        dir,
+    };
+
+    let client = reqwest::Client::new();
+    for _ in 0..100 {
+        let health = client.get(format!("{}/healthz", server.url)).send().await;
+        if health.is_ok_and(|r| r.status().is_success()) {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the server didn't start on {}", server.url);
+}
+
+#[tokio::test]
+async fn server_embeds_and_answers_with_the_fake() {
+    if std::env::var("LLM_PLAYGROUND_QDRANT_URL").is_err() {
+        eprintln!("LLM_PLAYGROUND_QDRANT_URL is not set, skipping the server test");
+        return;
+    }
+    let base_url = start_fake();
+    let server = start_server(&base_url).await;
+    let client = reqwest::Client::new();
+
+    let readiness: Readiness = client
+        .get(format!("{}/readyz", server.url))
+        .send()
+        .await
+        .expect("readiness request")
+        .json()
+        .await
+        .expect("readiness response");
+    for check in &readiness.checks {
+        assert!(check.ok, "{} failed: {:?}", check.name, check.error);
+    }
+    assert!(readiness.ready);
+    assert!(readiness.checks.iter().any(|c| c.name == "embeddings"));
+
+    let response = client
+        .post(format!(
+            "{}/api/v1/post_query_for_answer_of_a_question",
+            server.url
+        ))
+        .json(&json!({"text": "What is NARS2?", "topn": 3}))
+        .send()
+        .await
+        .expect("answer request");
+    assert!(response.status().is_success(), "{}", response.status());
+    let answer: Answer = response.json().await.expect("answer response");
+    assert_eq!(answer.text, REPLY);
+    assert!(!answer.sources.is_empty());
+    assert!(answer.unknown_citations.is_empty());
+    assert!(answer.sources[0].cited);
+    assert!(!answer.cache_hit);
+}
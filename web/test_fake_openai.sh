@@ -0,0 +1,84 @@
+#!/usr/bin/env bash
+# Run the server against the fake OpenAI server instead of api.openai.com.
+# Qdrant still has to be up with the collections (see `just start_qudrant`).
+set -euo pipefail
+IFS=$'\n\t'
+
+cargo build --bin server --bin fake_openai
+
+../target/debug/fake_openai --port 8089 &
+FAKE_PID=$!
+LLM_PLAYGROUND_OPENAI_BASE_URL=http://127.0.0.1:8089/v1 OPENAI_API_KEY=fake \
+    ../target/debug/server --port 3001 --config server.toml &
+SERVER_PID=$!
+trap 'kill $FAKE_PID $SERVER_PID 2>/dev/null' EXIT
+sleep 5
+
+curl -sf http://127.0.0.1:3001/api/openapi.json | head -c 300
+echo
+curl -sf http://127.0.0.1:3001/healthz
+echo
+curl -s http://127.0.0.1:3001/readyz
+echo
+# the unversioned routes still answer
+curl -sf http://127.0.0.1:3001/api/prompts > /dev/null
+curl -sf http://127.0.0.1:3001/api/v1/post_query_for_similarity_search -X POST -H "Content-Type: application/json" -d '{"text":"NARS2", "topn":3}'
+echo
+curl -sf http://127.0.0.1:3001/api/v1/post_query_for_answer_of_a_question -X POST -H "Content-Type: application/json" -d '{"text":"What is NARS2?", "topn":3}'
+echo
+curl -sfN http://127.0.0.1:3001/api/v1/stream_query_for_answer_of_a_question -X POST -H "Content-Type: application/json" -d '{"text":"What is NARS2?", "topn":3}'
+echo
+curl -sf http://127.0.0.1:3001/api/v1/prompts
+echo
+curl -sf http://127.0.0.1:3001/api/v1/post_query_for_answer_of_a_question -X POST -H "Content-Type: application/json" -d '{"text":"What is NARS2?", "topn":3, "prompt":"question_answering@v2"}'
+echo
+# the same question again, served from the cache with "cache_hit":true
+curl -sf http://127.0.0.1:3001/api/v1/post_query_for_answer_of_a_question -X POST -H "Content-Type: application/json" -d '{"text":"what is  NARS2", "topn":3}' | grep -o '"cache_hit":[a-z]*'
+curl -sf http://127.0.0.1:3001/api/v1/cache -X DELETE -o /dev/null -w "%{http_code}\n"
+curl -sf http://127.0.0.1:3001/api/v1/feedback -X POST -H "Content-Type: application/json" -d '{"log_id":2, "rating":"up", "comment":"cites the right section"}'
+echo
+curl -sf "http://127.0.0.1:3001/api/v1/logs/export?since=0"
+echo
+curl -sf "http://127.0.0.1:3001/api/v1/documents?limit=5"
+echo
+curl -sf "http://127.0.0.1:3001/api/v1/documents/0/sections?limit=5"
+echo
+curl -sf "http://127.0.0.1:3001/api/v1/documents/0/sections/0/chunks"
+echo
+curl -sf "http://127.0.0.1:3001/api/v1/documents/0/sections/0/chunks/0"
+echo
+UPLOAD=$(mktemp --suffix=.md)
+printf '# Test Syndrome\n\nTest syndrome is caused by variants in TEST1.\n\n## Management\n\n- physical therapy\n- follow-up\n' > "$UPLOAD"
+# answers 400 once the document is indexed, on later runs
+curl -s http://127.0.0.1:3001/api/v1/documents -F "file=@$UPLOAD;filename=test_syndrome.md"
+echo
+sleep 2
+curl -sf http://127.0.0.1:3001/api/v1/jobs
+echo
+curl -sf http://127.0.0.1:3001/api/v1/usage
+echo
+# a question over the WebSocket and a summary stopped after its first token,
+# needs `pip install websockets`
+python3 - <<'EOF'
+import json
+from websockets.sync.client import connect
+
+with connect("ws://127.0.0.1:3001/api/v1/ws") as ws:
+    print(json.loads(ws.recv()))
+    ws.send(json.dumps({"type": "query", "query": {"text": "What is NARS2?", "topn": 3}}))
+    while (event := json.loads(ws.recv()))["type"] not in ("done", "error"):
+        pass
+    print(event["type"])
+    ws.send(json.dumps({"type": "query", "kind": "summary", "query": {"text": "NARS2", "topn": 3}}))
+    while (event := json.loads(ws.recv()))["type"] != "token":
+        pass
+    ws.send(json.dumps({"type": "cancel"}))
+    while (event := json.loads(ws.recv()))["type"] not in ("done", "cancelled", "error"):
+        pass
+    print(event["type"])
+EOF
+curl -sf http://127.0.0.1:3001/metrics | grep -E '^llm_playground_(http_requests|llm_tokens|api_errors)_total'
+rm "$UPLOAD"
+# SIGTERM drains the requests in flight, the server exits cleanly
+kill -TERM $SERVER_PID
+wait $SERVER_PID && echo "server stopped"